@@ -0,0 +1,34 @@
+use std::{fs, time::Instant};
+
+use png_decode::*;
+
+// Times the inflate of the image data and the whole decode of every image in ./test_images.
+// Run it with `cargo run --release --example bench`.
+fn main() {
+    let mut paths: Vec<_> = fs::read_dir("./test_images").unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "png"))
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let bytes = fs::read(&path).unwrap();
+        let compressed: Vec<u8> = read_chunks(&bytes).unwrap().into_iter()
+            .filter(|chunk| chunk.typ == ChunkType::IDAT)
+            .flat_map(|chunk| chunk.data)
+            .collect();
+
+        let start = Instant::now();
+        let inflated = inflate(&compressed).unwrap();
+        let inflate_time = start.elapsed();
+
+        let start = Instant::now();
+        PNGImage::from_bytes(&bytes).unwrap();
+        let decode_time = start.elapsed();
+
+        println!("{}: {} bytes, inflate {:.1} ms ({:.1} MB/s), decode {:.1} ms",
+            path.file_name().unwrap().to_str().unwrap(), bytes.len(),
+            inflate_time.as_secs_f64() * 1000.0, inflated.len() as f64 / inflate_time.as_secs_f64() / 1e6,
+            decode_time.as_secs_f64() * 1000.0);
+    }
+}
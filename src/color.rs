@@ -0,0 +1,319 @@
+use std::array::from_fn;
+
+use crate::{png::*, Error};
+
+// gAMA, cHRM and sRGB tell the colour space of the samples. The spec puts them in order:
+//  * iCCP -> an ICC profile, the most precise one (we don´t read them).
+//  * sRGB -> the samples are already sRGB, gAMA and cHRM are only there for decoders that don´t know it.
+//  * gAMA and cHRM -> the transfer function and the primaries.
+//  * none of them -> the image is treated as sRGB.
+// Values of gAMA and cHRM are stored as integers, times 100000.
+
+/// gAMA: the exponent the linear samples were raised to, 1/2.2 is stored as 45455.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gamma(pub u32);
+
+impl Gamma {
+    pub fn value(&self) -> f64 {
+        self.0 as f64 / 100000.0
+    }
+}
+
+/// cHRM: CIE 1931 (x, y) chromaticities of the white point and of the three primaries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Chromaticities {
+    pub white: (u32, u32),
+    pub red: (u32, u32),
+    pub green: (u32, u32),
+    pub blue: (u32, u32),
+}
+
+/// sRGB: how colours out of the gamut of the display should be mapped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenderingIntent {
+    Perceptual,
+    RelativeColorimetric,
+    Saturation,
+    AbsoluteColorimetric,
+}
+
+type Matrix = [[f64; 3]; 3];
+
+impl Chromaticities {
+    /// ITU-R BT.709 primaries with the D65 white point, the ones of sRGB.
+    pub const SRGB: Chromaticities = Chromaticities {
+        white: (31270, 32900),
+        red: (64000, 33000),
+        green: (30000, 60000),
+        blue: (15000, 6000),
+    };
+
+    /// From linear RGB to CIE XYZ, None if the primaries don´t span a colour space.
+    fn rgb_to_xyz(&self) -> Option<Matrix> {
+        let [r, g, b] = [xyz(self.red)?, xyz(self.green)?, xyz(self.blue)?];
+        let primaries = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+        // each primary is scaled so that R = G = B = 1 gives the white point.
+        let scale = mul_vector(&invert(&primaries)?, xyz(self.white)?);
+        Some(from_fn(|i| from_fn(|j| primaries[i][j] * scale[j])))
+    }
+
+    /// From linear RGB in these primaries to linear sRGB, adapting the white point to D65.
+    fn to_srgb_matrix(self) -> Option<Matrix> {
+        let srgb = Chromaticities::SRGB;
+        let adapt = adaptation(xyz(self.white)?, xyz(srgb.white)?)?;
+        let m = multiply(&invert(&srgb.rgb_to_xyz()?)?, &multiply(&adapt, &self.rgb_to_xyz()?));
+        m.iter().flatten().all(|x| x.is_finite()).then_some(m)
+    }
+}
+
+/// XYZ of a chromaticity with luminance Y = 1.
+fn xyz((x, y): (u32, u32)) -> Option<[f64; 3]> {
+    if y == 0 {
+        return None;
+    }
+    let (x, y) = (x as f64 / 100000.0, y as f64 / 100000.0);
+    Some([x / y, 1.0, (1.0 - x - y) / y])
+}
+
+// Bradford transform, from XYZ to the cone responses used to move a colour between white points.
+const BRADFORD: Matrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+fn adaptation(from: [f64; 3], to: [f64; 3]) -> Option<Matrix> {
+    let (source, destination) = (mul_vector(&BRADFORD, from), mul_vector(&BRADFORD, to));
+    let scale = from_fn(|i| from_fn(|j| if i == j { destination[i] / source[i] } else { 0.0 }));
+    Some(multiply(&invert(&BRADFORD)?, &multiply(&scale, &BRADFORD)))
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    from_fn(|i| from_fn(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+fn mul_vector(m: &Matrix, v: [f64; 3]) -> [f64; 3] {
+    from_fn(|i| (0..3).map(|k| m[i][k] * v[k]).sum())
+}
+
+fn invert(m: &Matrix) -> Option<Matrix> {
+    // cofactor c[i][j], the transpose of it divided by the determinant is the inverse.
+    let c = |i: usize, j: usize| {
+        let (i1, i2, j1, j2) = ((i + 1) % 3, (i + 2) % 3, (j + 1) % 3, (j + 2) % 3);
+        m[i1][j1] * m[i2][j2] - m[i1][j2] * m[i2][j1]
+    };
+    let det: f64 = (0..3).map(|j| m[0][j] * c(0, j)).sum();
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    Some(from_fn(|i| from_fn(|j| c(j, i) / det)))
+}
+
+pub fn gamma_from_chunk(gama: &Chunk) -> Result<Gamma, Error> {
+    let invalid = |reason| Error::InvalidChunk { typ: gama.typ, offset: gama.offset, reason };
+    if gama.typ != ChunkType::gAMA {
+        return Err(invalid("expected gAMA"));
+    }
+    if gama.data.len() != 4 {
+        return Err(invalid("length must be 4"));
+    }
+    let gamma = u32::from_be_bytes(gama.data[..].try_into().expect("Valid length"));
+    if gamma == 0 {
+        return Err(invalid("gamma must not be 0"));
+    }
+    Ok(Gamma(gamma))
+}
+
+/// The values are in the order white x, white y, red x, red y, green x, green y, blue x, blue y.
+pub fn chromaticities_from_chunk(chrm: &Chunk) -> Result<Chromaticities, Error> {
+    let invalid = |reason| Error::InvalidChunk { typ: chrm.typ, offset: chrm.offset, reason };
+    if chrm.typ != ChunkType::cHRM {
+        return Err(invalid("expected cHRM"));
+    }
+    if chrm.data.len() != 32 {
+        return Err(invalid("length must be 32"));
+    }
+    let value = |i: usize| u32::from_be_bytes(chrm.data[4 * i..4 * i + 4].try_into().expect("Valid length"));
+    let chromaticities = Chromaticities {
+        white: (value(0), value(1)),
+        red: (value(2), value(3)),
+        green: (value(4), value(5)),
+        blue: (value(6), value(7)),
+    };
+    if chromaticities.to_srgb_matrix().is_none() {
+        return Err(invalid("the chromaticities don´t describe a colour space"));
+    }
+    Ok(chromaticities)
+}
+
+pub fn rendering_intent_from_chunk(srgb: &Chunk) -> Result<RenderingIntent, Error> {
+    let invalid = |reason| Error::InvalidChunk { typ: srgb.typ, offset: srgb.offset, reason };
+    if srgb.typ != ChunkType::sRGB {
+        return Err(invalid("expected sRGB"));
+    }
+    if srgb.data.len() != 1 {
+        return Err(invalid("length must be 1"));
+    }
+    use RenderingIntent::*;
+    match srgb.data[0] {
+        0 => Ok(Perceptual),
+        1 => Ok(RelativeColorimetric),
+        2 => Ok(Saturation),
+        3 => Ok(AbsoluteColorimetric),
+        _ => Err(invalid("invalid rendering intent")),
+    }
+}
+
+/// From linear light to the sRGB transfer function.
+fn linear_to_srgb(v: f64) -> f64 {
+    match v <= 0.0031308 {
+        true => 12.92 * v,
+        false => 1.055 * v.powf(1.0 / 2.4) - 0.055,
+    }
+}
+
+fn srgb_to_linear(v: f64) -> f64 {
+    match v <= 0.04045 {
+        true => v / 12.92,
+        false => ((v + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+fn quantize(v: f64, max: u32) -> u16 {
+    (v.clamp(0.0, 1.0) * max as f64).round() as u16
+}
+
+/// Whether the samples are sRGB already, as `PNGImage::convert_to_srgb` takes them.
+pub(crate) fn is_srgb(gamma: Option<Gamma>, chromaticities: Option<Chromaticities>, srgb: Option<RenderingIntent>) -> bool {
+    srgb.is_some() || (gamma.is_none() && chromaticities.is_none())
+}
+
+/// The conversion to sRGB of the pixels of an image, and of its palette.
+pub(crate) struct SrgbConversion {
+    header: ImageHeader,
+    gamma: Option<f64>,
+    matrix: Option<Matrix>,
+    levels: Vec<f64>, // every possible sample, decoded to linear light.
+    table: Vec<u16>,
+}
+
+impl SrgbConversion {
+    pub(crate) fn new(header: &ImageHeader, gamma: Option<Gamma>, chromaticities: Option<Chromaticities>) -> Self {
+        let gamma = gamma.map(|g| g.value());
+        // the chunk was checked when parsed, None only for the ones made by hand.
+        let matrix = chromaticities.and_then(Chromaticities::to_srgb_matrix);
+        let max = (1u32 << header.bit_depth) - 1;
+        let levels: Vec<f64> = (0..=max).map(|x| linear(gamma, x as f64 / max as f64)).collect();
+        let table = levels.iter().map(|&v| quantize(linear_to_srgb(v), max)).collect();
+        Self { header: *header, gamma, matrix, levels, table }
+    }
+
+    pub(crate) fn palette(&self, palette: &mut [[u8; 3]]) {
+        for entry in palette {
+            let rgb = entry.map(|x| linear(self.gamma, x as f64 / 255.0));
+            let rgb = match &self.matrix {
+                Some(m) => mul_vector(m, rgb),
+                None => rgb,
+            };
+            *entry = rgb.map(|x| quantize(linear_to_srgb(x), 255) as u8);
+        }
+    }
+
+    /// Pixels packed like the ones of `PNGImage::data`, whole rows of them.
+    pub(crate) fn pixels(&self, data: &mut [u8]) {
+        let header = self.header;
+        let max = (1u32 << header.bit_depth) - 1;
+        // grey has no primaries, white is white whatever the white point.
+        let convert = |s: &mut [u16]| match (&self.matrix, header.color_type) {
+            (_, ColorType::Greyscale | ColorType::GreyscaleA) => s[0] = self.table[s[0] as usize],
+            (Some(m), _) => {
+                let rgb = mul_vector(m, from_fn(|i| self.levels[s[i] as usize]));
+                for i in 0..3 {
+                    s[i] = quantize(linear_to_srgb(rgb[i]), max);
+                }
+            }
+            (None, _) => {
+                for x in &mut s[..3] {
+                    *x = self.table[*x as usize];
+                }
+            }
+        };
+        map_pixels(data, header.bit_depth, header.color_type.channels(), convert);
+    }
+}
+
+/// From a sample to linear light, with the gamma of the image or else the sRGB transfer function.
+fn linear(gamma: Option<f64>, v: f64) -> f64 {
+    match gamma {
+        Some(g) => v.powf(1.0 / g),
+        None => srgb_to_linear(v),
+    }
+}
+
+impl PNGImage {
+    /// Converts the samples (or the palette) to sRGB using the gAMA and cHRM chunks. Without
+    /// cHRM the primaries are taken as the sRGB ones, and without gAMA the transfer function
+    /// is the sRGB one. Images with an sRGB chunk, or without any of the three, are already
+    /// sRGB and stay as they are.
+    ///
+    /// Colour keys would stop matching the converted samples, so a tRNS chunk of a greyscale
+    /// or truecolour image is expanded first. Afterwards the image has an sRGB chunk instead
+    /// of gAMA and cHRM.
+    pub fn convert_to_srgb(&mut self) {
+        if is_srgb(self.gamma, self.chromaticities, self.srgb) {
+            return;
+        }
+        if matches!(self.transparency, Some(Transparency::Greyscale(_) | Transparency::Truecolour(..))) {
+            self.expand_trns();
+        }
+        let conversion = SrgbConversion::new(&self.header, self.gamma, self.chromaticities);
+        match self.header.color_type {
+            ColorType::Indexed => conversion.palette(self.palette.as_deref_mut().unwrap_or_default()),
+            // the frames of an animation have the same format as the image.
+            _ => {
+                let frames = self.animation.iter_mut().flat_map(|a| a.frames.iter_mut().map(|f| &mut f.data));
+                for data in std::iter::once(&mut self.data).chain(frames) {
+                    conversion.pixels(data);
+                }
+            }
+        }
+        self.gamma = None;
+        self.chromaticities = None;
+        self.srgb = Some(RenderingIntent::Perceptual);
+    }
+}
+
+/// Calls `f` with the samples of every pixel and stores them back, the alpha included.
+/// Samples smaller than a byte are only in greyscale images, so they come one by one.
+fn map_pixels(data: &mut [u8], bit_depth: u8, channels: usize, mut f: impl FnMut(&mut [u16])) {
+    if bit_depth < 8 {
+        let mask = (1u8 << bit_depth) - 1;
+        for byte in data.iter_mut() {
+            let mut out = 0;
+            for shift in (0..8).step_by(bit_depth as usize) {
+                let mut sample = [((*byte >> shift) & mask) as u16];
+                f(&mut sample);
+                out |= (sample[0] as u8) << shift;
+            }
+            *byte = out;
+        }
+        return;
+    }
+    let bytes = bit_depth as usize / 8;
+    for pixel in data.chunks_exact_mut(bytes * channels) {
+        let mut samples = [0u16; 4];
+        for (s, b) in samples.iter_mut().zip(pixel.chunks_exact(bytes)) {
+            *s = match bytes {
+                2 => u16::from_be_bytes([b[0], b[1]]),
+                _ => b[0] as u16,
+            };
+        }
+        f(&mut samples[..channels]);
+        for (s, b) in samples.iter().zip(pixel.chunks_exact_mut(bytes)) {
+            match bytes {
+                2 => b.copy_from_slice(&s.to_be_bytes()),
+                _ => b[0] = *s as u8,
+            }
+        }
+    }
+}
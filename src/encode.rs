@@ -0,0 +1,308 @@
+use std::{fs::File, io::Write};
+
+use crate::{png::*, zlib, Error};
+
+// Encoding is decoding backwards: the Adam7 passes are taken apart again when the image is
+// interlaced, every scanline is filtered, and the zlib stream is cut in IDAT chunks.
+// Only the chunks needed for the pixels are written: IHDR, PLTE, tRNS, IDAT and IEND.
+
+/// How the filter type of each scanline is chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum FilterStrategy {
+    /// The same filter type for every scanline, from 0 (None) to 4 (Paeth).
+    Fixed(u8),
+    /// The filter that gives the smallest sum of the filtered bytes, taken as signed, like libpng.
+    /// Indexed-colour images and the ones with samples smaller than a byte are not filtered,
+    /// as the spec recommends, filters rarely help them.
+    #[default]
+    MinSum,
+    /// Compresses the scanline, after the one before it, with every filter and keeps the
+    /// smallest. Slow, but the output is usually smaller.
+    BruteForce,
+}
+
+#[derive(Clone, Debug)]
+pub struct EncodeOptions {
+    /// Most compressed bytes in each IDAT chunk, the last one may have less.
+    pub idat_size: usize,
+    /// From 0, stored without compression, to 9, the smallest and slowest.
+    pub compression: u8,
+    pub filter: FilterStrategy,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self { idat_size: 8192, compression: 6, filter: FilterStrategy::default() }
+    }
+}
+
+impl PNGImage {
+    pub fn to_path(&self, path: &str) -> Result<(), Error> {
+        self.write_to(File::create(path)?)
+    }
+
+    pub fn write_to(&self, writer: impl Write) -> Result<(), Error> {
+        self.write_to_with(writer, &EncodeOptions::default())
+    }
+
+    pub fn write_to_with(&self, mut writer: impl Write, options: &EncodeOptions) -> Result<(), Error> {
+        writer.write_all(&self.to_bytes_with(options)?)?;
+        Ok(())
+    }
+
+    /// The image as a PNG datastream. Animations and the ancillary chunks other than tRNS are not
+    /// written, only the default image. The fields are checked with the same functions the decoder
+    /// uses, so an image made by hand that breaks the spec is an error, with offsets into the output.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        self.to_bytes_with(&EncodeOptions::default())
+    }
+
+    pub fn to_bytes_with(&self, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        for chunk in self.to_chunks_with(options)? {
+            bytes.extend_from_slice(&chunk.to_bytes());
+        }
+        Ok(bytes)
+    }
+
+    /// The chunks `to_bytes_with` writes, with their offsets in the datastream.
+    pub fn to_chunks_with(&self, options: &EncodeOptions) -> Result<Vec<Chunk>, Error> {
+        let header = self.header;
+        let mut chunks = vec![];
+
+        let color_type = match header.color_type {
+            ColorType::Greyscale => 0,
+            ColorType::Truecolour => 2,
+            ColorType::Indexed => 3,
+            ColorType::GreyscaleA => 4,
+            ColorType::TruecolourA => 6,
+        };
+        let interlace = match header.interlace {
+            Interlace::None => 0,
+            Interlace::Adam7 => 1,
+        };
+        let mut ihdr = [header.width.to_be_bytes(), header.height.to_be_bytes()].concat();
+        ihdr.extend_from_slice(&[header.bit_depth, color_type, 0, 0, interlace]);
+        ImageHeader::from_chunk(write_chunk(&mut chunks, b"IHDR", ihdr)?.clone())?;
+
+        // greyscale images can´t have a palette, truecolour ones may suggest one.
+        let palette = match (header.color_type, &self.palette) {
+            (ColorType::Greyscale | ColorType::GreyscaleA, _) | (_, None) => None,
+            (_, Some(palette)) => Some(palette_from_chunk(write_chunk(&mut chunks, b"PLTE", palette.concat())?)?),
+        };
+        if header.color_type == ColorType::Indexed && palette.is_none() {
+            return Err(Error::MissingChunk { typ: ChunkType::PLTE });
+        }
+        if let Some(trns) = &self.transparency {
+            let data = match trns {
+                Transparency::Greyscale(grey) => grey.to_be_bytes().to_vec(),
+                Transparency::Truecolour(r, g, b) => [r, g, b].iter().flat_map(|x| x.to_be_bytes()).collect(),
+                Transparency::Indexed(alpha) => alpha.clone(),
+            };
+            let chunk = write_chunk(&mut chunks, b"tRNS", data)?;
+            if transparency_from_chunk(chunk, &header, palette.as_deref())? != *trns {
+                return Err(Error::InvalidChunk { typ: chunk.typ, offset: chunk.offset, reason: "does not match the colour type" });
+            }
+        }
+
+        let size = header.row_bytes().saturating_mul(header.height as usize);
+        if self.data.len() < size {
+            return Err(Error::TruncatedImageData { offset: self.data.len() });
+        }
+        if let FilterStrategy::Fixed(filter @ 5..) = options.filter {
+            return Err(Error::InvalidFilterType { filter, offset: 0 });
+        }
+        let compressed = zlib::deflate(&scanlines(&header, &self.data[..size], options), options.compression);
+        for piece in compressed.chunks(options.idat_size.max(1)) {
+            write_chunk(&mut chunks, b"IDAT", piece.to_vec())?;
+        }
+        write_chunk(&mut chunks, b"IEND", vec![])?;
+        Ok(chunks)
+    }
+}
+
+/// Adds the chunk after the others and returns it, to check it like when decoding.
+fn write_chunk<'a>(chunks: &'a mut Vec<Chunk>, name: &[u8; 4], data: Vec<u8>) -> Result<&'a Chunk, Error> {
+    let offset = chunks.last().map(|c| c.offset + c.data.len() + 12).unwrap_or(8);
+    chunks.push(Chunk::new(*name, data, offset)?);
+    Ok(chunks.last().expect("Just added"))
+}
+
+/// The scanlines ready to be compressed, filtered and with their filter type byte, pass by pass for Adam7 images.
+fn scanlines(header: &ImageHeader, data: &[u8], options: &EncodeOptions) -> Vec<u8> {
+    let strategy = match options.filter {
+        FilterStrategy::MinSum if header.color_type == ColorType::Indexed || header.bit_depth < 8 => FilterStrategy::Fixed(0),
+        strategy => strategy,
+    };
+    // brute force with stored blocks would find every filter the same.
+    let filter = |res: &mut Vec<u8>, data: &[u8], cols: usize| {
+        filter(res, data, cols, header.bits_per_pixel().div_ceil(8), strategy, options.compression.max(1));
+    };
+    // the data was checked to be there, so it fits.
+    let mut res = Vec::with_capacity(header.data_size().unwrap_or_default());
+    if header.interlace == Interlace::None {
+        filter(&mut res, data, header.row_bytes());
+        return res;
+    }
+    let (width, height) = (header.width as usize, header.height as usize);
+    for pass in 0..7 {
+        let (w, h) = adam7_pass_size(width, height, pass);
+        if w == 0 || h == 0 {
+            continue;
+        }
+        let cols = (w * header.bits_per_pixel()).div_ceil(8);
+        filter(&mut res, &adam7_pass(header, data, pass), cols);
+    }
+    res
+}
+
+/// The reduced image of an Adam7 pass, the other way around of what `PNGImage::reconstruct` does.
+fn adam7_pass(header: &ImageHeader, data: &[u8], pass: usize) -> Vec<u8> {
+    let bits_per_pixel = header.bits_per_pixel();
+    let bytes_per_pixel = bits_per_pixel.div_ceil(8);
+    let row_bytes = header.row_bytes();
+    let (x0, y0, dx, dy) = ADAM7[pass];
+    let (w, h) = adam7_pass_size(header.width as usize, header.height as usize, pass);
+    let cols = (w * bits_per_pixel).div_ceil(8);
+    let mut reduced = vec![0u8; h * cols];
+    for (i, row) in reduced.chunks_mut(cols).enumerate() {
+        let y = y0 + i * dy;
+        let src = &data[y * row_bytes..(y + 1) * row_bytes];
+        for j in 0..w {
+            let x = x0 + j * dx;
+            if bits_per_pixel >= 8 {
+                row[j * bytes_per_pixel..(j + 1) * bytes_per_pixel]
+                    .copy_from_slice(&src[x * bytes_per_pixel..(x + 1) * bytes_per_pixel]);
+            } else {
+                let mask = (1u8 << bits_per_pixel) - 1;
+                let src_shift = 8 - bits_per_pixel * (x % (8 / bits_per_pixel) + 1);
+                let dst_shift = 8 - bits_per_pixel * (j % (8 / bits_per_pixel) + 1);
+                let value = (src[x * bits_per_pixel / 8] >> src_shift) & mask;
+                row[j * bits_per_pixel / 8] |= value << dst_shift;
+            }
+        }
+    }
+    reduced
+}
+
+/// Adds the rows of `data`, `cols` bytes each, filtered with the type the strategy picks for each one.
+fn filter(res: &mut Vec<u8>, data: &[u8], cols: usize, bytes_per_pixel: usize, strategy: FilterStrategy, level: u8) {
+    let mut previous: &[u8] = &[];
+    let mut previous_line = vec![];
+    for row in data.chunks(cols) {
+        let filtered = |typ: u8| {
+            let mut line = Vec::with_capacity(cols + 1);
+            line.push(typ);
+            PNGImage::filter_row(typ, row, previous, bytes_per_pixel, &mut line);
+            line
+        };
+        // ties go to the first one, the simplest filter.
+        let line = match strategy {
+            FilterStrategy::Fixed(typ) => filtered(typ),
+            FilterStrategy::MinSum => (0..5).map(filtered)
+                .min_by_key(|line| line[1..].iter().map(|&x| (x as i8).unsigned_abs() as u64).sum::<u64>())
+                .expect("Five filters"),
+            // the scanline before lets the matches reach the row above, like in the whole stream.
+            FilterStrategy::BruteForce => (0..5).map(filtered)
+                .min_by_key(|line| zlib::deflate(&[&previous_line[..], line].concat(), level).len())
+                .expect("Five filters"),
+        };
+        res.extend_from_slice(&line);
+        previous_line = line;
+        previous = row;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{test_util::noise, Metadata};
+
+    fn image(color_type: ColorType, bit_depth: u8, interlace: Interlace, width: u32, height: u32) -> PNGImage {
+        let header = ImageHeader { width, height, bit_depth, color_type, interlace };
+        let row_bytes = header.row_bytes();
+        // few different values, so that deflate finds matches too.
+        let mut data: Vec<u8> = noise(row_bytes * height as usize, width * 31 + height, 8).iter().map(|x| x * 36).collect();
+        // the decoder gives the padding at the end of a row as zeros.
+        let bits = width as usize * header.bits_per_pixel() % 8;
+        if bits != 0 {
+            for row in data.chunks_mut(row_bytes) {
+                row[row_bytes - 1] &= !(0xff >> bits);
+            }
+        }
+        let palette = (color_type == ColorType::Indexed).then(|| (0..1 << bit_depth).map(|i| [i as u8, !i as u8, 7]).collect());
+        PNGImage {
+            header,
+            bytes_per_pixel: header.bits_per_pixel().div_ceil(8) as u8,
+            palette,
+            transparency: None,
+            gamma: None,
+            chromaticities: None,
+            srgb: None,
+            text: HashMap::new(),
+            metadata: Metadata::default(),
+            unknown_chunks: vec![],
+            animation: None,
+            data,
+            warnings: vec![],
+        }
+    }
+
+    fn assert_round_trip(image: &PNGImage, options: &EncodeOptions) {
+        let decoded = PNGImage::from_bytes(&image.to_bytes_with(options).unwrap()).unwrap();
+        assert_eq!(decoded.header, image.header);
+        assert_eq!(decoded.palette, image.palette);
+        assert!(decoded.data == image.data, "{:?} with {:?}", image.header, options);
+    }
+
+    #[test]
+    fn round_trip_every_format() {
+        let formats = [
+            (ColorType::Greyscale, &[1, 2, 4, 8, 16][..]),
+            (ColorType::Truecolour, &[8, 16]),
+            (ColorType::Indexed, &[1, 2, 4, 8]),
+            (ColorType::GreyscaleA, &[8, 16]),
+            (ColorType::TruecolourA, &[8, 16]),
+        ];
+        for (color_type, bit_depths) in formats {
+            for &bit_depth in bit_depths {
+                for interlace in [Interlace::None, Interlace::Adam7] {
+                    for (width, height) in [(1, 1), (7, 5), (33, 17)] {
+                        assert_round_trip(&image(color_type, bit_depth, interlace, width, height), &EncodeOptions::default());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_every_filter_and_level() {
+        let image = image(ColorType::Truecolour, 8, Interlace::Adam7, 40, 30);
+        let filters = (0..5).map(FilterStrategy::Fixed).chain([FilterStrategy::MinSum, FilterStrategy::BruteForce]);
+        for filter in filters {
+            for compression in 0..=9 {
+                // small chunks, so that the stream is split in many IDAT chunks.
+                assert_round_trip(&image, &EncodeOptions { idat_size: 100, compression, filter });
+            }
+        }
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let inputs = [
+            vec![],
+            vec![42],
+            vec![0; 100_000],
+            noise(100_000, 1, 8),
+            noise(100_000, 2, 256),
+            (0..70_000).map(|i| (i % 251) as u8).collect(),
+        ];
+        for data in &inputs {
+            for level in 0..=9 {
+                assert!(zlib::inflate(&zlib::deflate(data, level)).unwrap() == *data, "{} bytes at level {}", data.len(), level);
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::{fmt, io};
+
+use crate::png::ChunkType;
+
+// Every offset is the position in bytes where the decoding stopped:
+//  * chunk errors -> from the start of the PNG datastream (the signature is at 0).
+//  * deflate errors -> from the start of the zlib stream, so the IDAT data for images.
+//  * filter and image data errors -> from the start of the inflated scanlines.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    BadSignature,
+    TruncatedChunk { offset: usize },
+    CrcMismatch { typ: ChunkType, offset: usize, stored: u32, computed: u32 },
+    /// The Adler-32 at the end of a zlib stream is not the one of the inflated data.
+    /// The offset is the one of the checksum in the zlib stream.
+    Adler32Mismatch { offset: usize, stored: u32, computed: u32 },
+    UnknownCriticalChunk { typ: [u8; 4], offset: usize },
+    /// Chunk types are made of four ASCII letters.
+    InvalidChunkName { name: [u8; 4], offset: usize },
+    InvalidChunk { typ: ChunkType, offset: usize, reason: &'static str },
+    MissingChunk { typ: ChunkType },
+    InvalidFilterType { filter: u8, offset: usize },
+    TruncatedImageData { offset: usize },
+    DeflateError { offset: usize, reason: &'static str },
+    /// Decoding would go past one of the `Limits`. The offset is the one of the chunk, or
+    /// the position in the zlib stream when it is the inflated data that is too big.
+    LimitExceeded { limit: &'static str, offset: usize },
+}
+
+impl Error {
+    pub fn offset(&self) -> Option<usize> {
+        use Error::*;
+        match self {
+            Io(_) | MissingChunk { .. } => None,
+            BadSignature => Some(0),
+            TruncatedChunk { offset }
+            | CrcMismatch { offset, .. }
+            | Adler32Mismatch { offset, .. }
+            | UnknownCriticalChunk { offset, .. }
+            | InvalidChunkName { offset, .. }
+            | InvalidChunk { offset, .. }
+            | InvalidFilterType { offset, .. }
+            | TruncatedImageData { offset }
+            | DeflateError { offset, .. }
+            | LimitExceeded { offset, .. } => Some(*offset),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            Io(e) => write!(f, "io error: {}", e),
+            BadSignature => write!(f, "not a PNG file, invalid signature"),
+            TruncatedChunk { offset } => write!(f, "chunk at offset {} goes past the end of the data", offset),
+            CrcMismatch { typ, offset, stored, computed } => write!(
+                f, "CRC mismatch in {:?} chunk at offset {}: stored {:08x}, computed {:08x}", typ, offset, stored, computed
+            ),
+            Adler32Mismatch { offset, stored, computed } => write!(
+                f, "Adler-32 mismatch at offset {}: stored {:08x}, computed {:08x}", offset, stored, computed
+            ),
+            UnknownCriticalChunk { typ, offset } => write!(
+                f, "unknown critical chunk {:?} at offset {}", String::from_utf8_lossy(typ), offset
+            ),
+            InvalidChunkName { name, offset } => write!(
+                f, "invalid chunk type {:?} at offset {}", String::from_utf8_lossy(name), offset
+            ),
+            InvalidChunk { typ, offset, reason } => write!(f, "invalid {:?} chunk at offset {}: {}", typ, offset, reason),
+            MissingChunk { typ } => write!(f, "missing {:?} chunk", typ),
+            InvalidFilterType { filter, offset } => write!(f, "invalid filter type {} at offset {}", filter, offset),
+            TruncatedImageData { offset } => write!(f, "image data ends too soon, at offset {}", offset),
+            DeflateError { offset, reason } => write!(f, "invalid deflate stream at offset {}: {}", offset, reason),
+            LimitExceeded { limit, offset } => write!(f, "limit exceeded at offset {}: {}", offset, limit),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
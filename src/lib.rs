@@ -1,10 +1,28 @@
-mod printer;
-mod png;
-mod zlib;
-//mod jpg;
-
-pub use std::fs;
-pub use png::*;
-pub use printer::*;
-pub use zlib::*;
-//pub use jpg::*;
+mod color;
+mod encode;
+mod error;
+mod metadata;
+mod optimize;
+mod printer;
+mod png;
+mod stream;
+#[cfg(test)]
+mod test_util;
+mod text;
+mod validate;
+mod zlib;
+//mod jpg;
+
+pub use std::fs;
+pub use color::*;
+pub use encode::*;
+pub use error::*;
+pub use metadata::*;
+pub use optimize::*;
+pub use png::*;
+pub use printer::*;
+pub use stream::*;
+pub use text::*;
+pub use validate::*;
+pub use zlib::*;
+//pub use jpg::*;
@@ -1,24 +1,42 @@
-use png_decode::*;
-fn main() {
-    let photos = fs::read_dir("./test_images").unwrap();
-
-    for photo in photos {
-
-        let name = photo.unwrap().path();
-        let name = name.to_str().unwrap();
-
-        match &name[name.len()-4..] {
-            ".jpg" => println!("Nope :)"),
-            ".png" => {
-                let img= PNGImage::from_path(name);
-                //println!("{:?}", img.header);
-                let hd = img.header;
-                let img = ImgData::new(hd.width, hd.height, &img.data, hd.color_type, hd.bit_depth);
-                let (width, _height) = crossterm::terminal::size().expect("No acces to shell");
-                let img = resize(img, width as f32);
-                print(img);
-            }
-            n => println!("Extension {} not supported", n),
-        }
-    }
-}
+use png_decode::*;
+fn main() {
+    let photos = fs::read_dir("./test_images").unwrap();
+
+    for photo in photos {
+
+        let name = photo.unwrap().path();
+        let name = name.to_str().unwrap();
+
+        match &name[name.len()-4..] {
+            ".jpg" => println!("Nope :)"),
+            ".png" => {
+                if let Err(e) = print_png(name) {
+                    println!("Could not print {}: {}", name, e);
+                }
+            }
+            n => println!("Extension {} not supported", n),
+        }
+    }
+}
+
+fn print_png(name: &str) -> Result<(), Error> {
+    // the terminal shows sRGB colours.
+    let options = DecodeOptions { convert_to_srgb: true, ..Default::default() };
+    let img= PNGImage::from_path_with(name, &options)?;
+    //println!("{:?}", img.header);
+    let hd = img.header;
+    if let Some(animation) = &img.animation {
+        let frames = img.frames()
+            .map(|frame| Ok((ImgData::from_rgba8(hd.width, hd.height, &frame.pixels)?, frame.delay)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        // animations are drawn in place, so the whole image must fit in the terminal (and resize only shrinks).
+        let (width, height) = crossterm::terminal::size()?;
+        let fit = 2.0 * height.saturating_sub(1) as f32 * hd.width as f32 / hd.height as f32;
+        let t_width = (width as f32).min(fit).min(hd.width as f32).max(1.0);
+        return play(frames, t_width, animation.control.num_plays);
+    }
+    let img = ImgData::new(hd.width, hd.height, &img.data, hd.color_type, hd.bit_depth, img.palette.as_deref(), img.transparency.as_ref())?;
+    let (width, _height) = crossterm::terminal::size()?;
+    let img = resize(img, width as f32);
+    print(img)
+}
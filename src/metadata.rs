@@ -0,0 +1,350 @@
+use crate::{png::*, text::*, Error};
+
+// Where the spec lets each chunk be, and how many of them:
+//  * tIME -> anywhere, one.
+//  * pHYs, eXIf -> before IDAT, one.
+//  * sBIT -> before PLTE and IDAT, one.
+//  * bKGD, hIST -> after PLTE and before IDAT, one.
+//  * sPLT -> before IDAT, any number with different names.
+
+/// tIME: when the image was last modified, in UTC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Time {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    /// Up to 60, for leap seconds.
+    pub second: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    /// Only the aspect ratio of the pixels is known.
+    Unknown,
+    Metre,
+}
+
+/// pHYs: pixels per unit on each axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PhysicalDimensions {
+    pub x: u32,
+    pub y: u32,
+    pub unit: Unit,
+}
+
+impl PhysicalDimensions {
+    /// Pixels per inch on each axis, when the unit is known.
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        match self.unit {
+            Unit::Metre => Some((self.x as f64 * 0.0254, self.y as f64 * 0.0254)),
+            Unit::Unknown => None,
+        }
+    }
+}
+
+/// bKGD: the colour to show the image on, in the format of the samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Background {
+    /// Also for greyscale with alpha.
+    Greyscale(u16),
+    /// Also for truecolour with alpha.
+    Truecolour(u16, u16, u16),
+    /// Index into the palette.
+    Indexed(u8),
+}
+
+/// sPLT: a palette for displays that cannot show every colour of the image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SuggestedPalette {
+    pub name: String,
+    /// 8 or 16, the samples of the entries use the whole range of it.
+    pub depth: u8,
+    pub entries: Vec<SuggestedEntry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SuggestedEntry {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+    pub alpha: u16,
+    /// Proportional to how often the colour is in the image, 0 when not known.
+    pub frequency: u16,
+}
+
+/// The ancillary chunks that don´t change how the pixels are decoded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub time: Option<Time>,
+    pub physical: Option<PhysicalDimensions>,
+    /// sBIT: bits of each channel that were meaningful in the original image, in the
+    /// order of the samples ([R, G, B] for indexed-colour images, of the palette entries).
+    pub significant_bits: Option<Vec<u8>>,
+    pub background: Option<Background>,
+    /// hIST: how often each palette entry is used, one value per entry.
+    pub histogram: Option<Vec<u16>>,
+    pub suggested_palettes: Vec<SuggestedPalette>,
+    /// eXIf: an Exif profile, starting with the TIFF header ("II*\0" or "MM\0*").
+    pub exif: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Reads the metadata without inflating the image data. Unlike when decoding
+    /// the image, where they are ignored with a warning, invalid chunks are errors here.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Metadata, Error> {
+        let chunks = read_chunks(bytes)?;
+        if let Some(chunk) = chunks.iter().find(|c| !c.crc_ok()) {
+            return Err(Error::CrcMismatch { typ: chunk.typ, offset: chunk.offset, stored: chunk.crc, computed: chunk.computed_crc() });
+        }
+        let header = ImageHeader::from_chunk(chunks.first().ok_or(Error::MissingChunk { typ: ChunkType::IHDR })?.clone())?;
+        let mut metadata = Metadata::default();
+        let mut palette = None;
+        let mut image_data = false;
+        for chunk in &chunks {
+            match chunk.typ {
+                ChunkType::PLTE => palette = Some(palette_from_chunk(chunk)?),
+                ChunkType::IDAT => image_data = true,
+                _ => metadata.read_chunk(chunk, &header, palette.as_deref(), image_data)?,
+            }
+        }
+        Ok(metadata)
+    }
+
+    /// Adds `chunk` if it is one of the metadata ones. `palette` is the PLTE chunk if it
+    /// came before this one, and `image_data` whether any IDAT did.
+    pub fn read_chunk(&mut self, chunk: &Chunk, header: &ImageHeader, palette: Option<&[[u8; 3]]>, image_data: bool) -> Result<(), Error> {
+        let invalid = |reason| Error::InvalidChunk { typ: chunk.typ, offset: chunk.offset, reason };
+        let data = &chunk.data;
+        let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().expect("Valid length"));
+        use ChunkType::*;
+        let before_image_data = matches!(chunk.typ, pHYs | sBIT | bKGD | hIST | sPLT | eXIf);
+        if before_image_data && image_data {
+            return Err(invalid("must come before IDAT"));
+        }
+        match chunk.typ {
+            tIME => {
+                if self.time.is_some() {
+                    return Err(invalid("only one is allowed"));
+                }
+                if data.len() != 7 {
+                    return Err(invalid("length must be 7"));
+                }
+                let time = Time { year: u16_at(0), month: data[2], day: data[3], hour: data[4], minute: data[5], second: data[6] };
+                if !(1..=12).contains(&time.month) || !(1..=31).contains(&time.day) || time.hour > 23 || time.minute > 59 || time.second > 60 {
+                    return Err(invalid("date or time out of range"));
+                }
+                self.time = Some(time);
+            }
+            pHYs => {
+                if self.physical.is_some() {
+                    return Err(invalid("only one is allowed"));
+                }
+                if data.len() != 9 {
+                    return Err(invalid("length must be 9"));
+                }
+                let unit = match data[8] {
+                    0 => Unit::Unknown,
+                    1 => Unit::Metre,
+                    _ => return Err(invalid("invalid unit")),
+                };
+                self.physical = Some(PhysicalDimensions { x: u32_at(0), y: u32_at(4), unit });
+            }
+            sBIT => {
+                if self.significant_bits.is_some() {
+                    return Err(invalid("only one is allowed"));
+                }
+                if palette.is_some() {
+                    return Err(invalid("must come before PLTE"));
+                }
+                let (channels, max) = match header.color_type {
+                    // palette entries are always 8 bits.
+                    ColorType::Indexed => (3, 8),
+                    typ => (typ.channels(), header.bit_depth),
+                };
+                if data.len() != channels {
+                    return Err(invalid("length must be the number of channels"));
+                }
+                if data.iter().any(|&bits| bits == 0 || bits > max) {
+                    return Err(invalid("significant bits must be between 1 and the sample depth"));
+                }
+                self.significant_bits = Some(data.clone());
+            }
+            bKGD => {
+                if self.background.is_some() {
+                    return Err(invalid("only one is allowed"));
+                }
+                let max = ((1u32 << header.bit_depth) - 1) as u16;
+                let background = match header.color_type {
+                    ColorType::Greyscale | ColorType::GreyscaleA => {
+                        if data.len() != 2 {
+                            return Err(invalid("length must be 2 for greyscale images"));
+                        }
+                        Background::Greyscale(u16_at(0))
+                    }
+                    ColorType::Truecolour | ColorType::TruecolourA => {
+                        if data.len() != 6 {
+                            return Err(invalid("length must be 6 for truecolour images"));
+                        }
+                        Background::Truecolour(u16_at(0), u16_at(2), u16_at(4))
+                    }
+                    ColorType::Indexed => {
+                        let palette = palette.ok_or(invalid("must come after PLTE"))?;
+                        if data.len() != 1 {
+                            return Err(invalid("length must be 1 for indexed-colour images"));
+                        }
+                        if data[0] as usize >= palette.len() {
+                            return Err(invalid("index out of the palette"));
+                        }
+                        Background::Indexed(data[0])
+                    }
+                };
+                let samples = match background {
+                    Background::Greyscale(x) => vec![x],
+                    Background::Truecolour(r, g, b) => vec![r, g, b],
+                    Background::Indexed(_) => vec![],
+                };
+                if samples.iter().any(|&x| x > max) {
+                    return Err(invalid("sample out of the range of the bit depth"));
+                }
+                self.background = Some(background);
+            }
+            hIST => {
+                if self.histogram.is_some() {
+                    return Err(invalid("only one is allowed"));
+                }
+                let palette = palette.ok_or(invalid("must come after PLTE"))?;
+                if data.len() != 2 * palette.len() {
+                    return Err(invalid("length must be 2 bytes per palette entry"));
+                }
+                self.histogram = Some((0..palette.len()).map(|i| u16_at(2 * i)).collect());
+            }
+            sPLT => {
+                let (name, rest) = split_null(data).ok_or(invalid("missing null separator after the name"))?;
+                if !valid_keyword(name) {
+                    return Err(invalid("invalid palette name"));
+                }
+                let name = latin1(name);
+                if self.suggested_palettes.iter().any(|p| p.name == name) {
+                    return Err(invalid("two suggested palettes with the same name"));
+                }
+                let (&depth, entries) = rest.split_first().ok_or(invalid("missing sample depth"))?;
+                // [R, G, B, A] of the sample depth and a 2 byte frequency.
+                let size = match depth {
+                    8 => 6,
+                    16 => 10,
+                    _ => return Err(invalid("sample depth must be 8 or 16")),
+                };
+                if !entries.len().is_multiple_of(size) {
+                    return Err(invalid("length of the entries must be a multiple of their size"));
+                }
+                let entries = entries.chunks_exact(size).map(|e| {
+                    let sample = |i: usize| match depth {
+                        8 => e[i] as u16,
+                        _ => u16::from_be_bytes([e[2 * i], e[2 * i + 1]]),
+                    };
+                    let frequency = u16::from_be_bytes([e[size - 2], e[size - 1]]);
+                    SuggestedEntry { red: sample(0), green: sample(1), blue: sample(2), alpha: sample(3), frequency }
+                }).collect();
+                self.suggested_palettes.push(SuggestedPalette { name, depth, entries });
+            }
+            eXIf => {
+                if self.exif.is_some() {
+                    return Err(invalid("only one is allowed"));
+                }
+                if !data.starts_with(b"II*\0") && !data.starts_with(b"MM\0*") {
+                    return Err(invalid("must start with a TIFF header"));
+                }
+                self.exif = Some(data.clone());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRUECOLOUR: ImageHeader = ImageHeader { width: 1, height: 1, bit_depth: 8, color_type: ColorType::Truecolour, interlace: Interlace::None };
+
+    // the metadata of `chunks`, all read after PLTE and before IDAT.
+    fn read(header: &ImageHeader, chunks: &[(&[u8; 4], &[u8])]) -> Result<Metadata, Error> {
+        let palette = [[0, 0, 0], [255, 255, 255]];
+        let mut metadata = Metadata::default();
+        for (name, data) in chunks {
+            metadata.read_chunk(&Chunk::new(**name, data.to_vec(), 33)?, header, Some(&palette), false)?;
+        }
+        Ok(metadata)
+    }
+
+    fn reason(result: Result<Metadata, Error>) -> &'static str {
+        match result {
+            Err(Error::InvalidChunk { reason, offset: 33, .. }) => reason,
+            result => panic!("{result:?}"),
+        }
+    }
+
+    #[test]
+    fn time() {
+        let metadata = read(&TRUECOLOUR, &[(b"tIME", &[0x07, 0xea, 10, 18, 23, 59, 60])]).unwrap();
+        assert_eq!(metadata.time, Some(Time { year: 2026, month: 10, day: 18, hour: 23, minute: 59, second: 60 }));
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"tIME", &[0x07, 0xea, 10, 18, 23, 59])])), "length must be 7");
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"tIME", &[0x07, 0xea, 13, 18, 23, 59, 0])])), "date or time out of range");
+        let twice: &[u8] = &[0x07, 0xea, 10, 18, 0, 0, 0];
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"tIME", twice), (b"tIME", twice)])), "only one is allowed");
+    }
+
+    #[test]
+    fn physical_dimensions() {
+        let metadata = read(&TRUECOLOUR, &[(b"pHYs", &[0, 0, 0x0b, 0x13, 0, 0, 0x16, 0x26, 1])]).unwrap();
+        let physical = metadata.physical.unwrap();
+        assert_eq!(physical, PhysicalDimensions { x: 2835, y: 5670, unit: Unit::Metre });
+        let (x, y) = physical.dpi().unwrap();
+        assert_eq!((x.round(), y.round()), (72.0, 144.0));
+        assert_eq!(read(&TRUECOLOUR, &[(b"pHYs", &[0, 0, 0, 1, 0, 0, 0, 2, 0])]).unwrap().physical.unwrap().dpi(), None);
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"pHYs", &[0, 0, 0, 1, 0, 0, 0, 2])])), "length must be 9");
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"pHYs", &[0, 0, 0, 1, 0, 0, 0, 2, 2])])), "invalid unit");
+    }
+
+    #[test]
+    fn background() {
+        let grey = ImageHeader { bit_depth: 4, color_type: ColorType::Greyscale, ..TRUECOLOUR };
+        let indexed = ImageHeader { color_type: ColorType::Indexed, ..TRUECOLOUR };
+        assert_eq!(read(&grey, &[(b"bKGD", &[0, 15])]).unwrap().background, Some(Background::Greyscale(15)));
+        assert_eq!(read(&TRUECOLOUR, &[(b"bKGD", &[0, 1, 0, 2, 0, 255])]).unwrap().background, Some(Background::Truecolour(1, 2, 255)));
+        assert_eq!(read(&indexed, &[(b"bKGD", &[1])]).unwrap().background, Some(Background::Indexed(1)));
+
+        assert_eq!(reason(read(&grey, &[(b"bKGD", &[15])])), "length must be 2 for greyscale images");
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"bKGD", &[0, 1, 0, 2, 0])])), "length must be 6 for truecolour images");
+        assert_eq!(reason(read(&indexed, &[(b"bKGD", &[])])), "length must be 1 for indexed-colour images");
+        assert_eq!(reason(read(&grey, &[(b"bKGD", &[0, 16])])), "sample out of the range of the bit depth");
+        assert_eq!(reason(read(&indexed, &[(b"bKGD", &[2])])), "index out of the palette");
+    }
+
+    #[test]
+    fn suggested_palettes() {
+        let bytes: &[u8] = &[0, 1, 0, 2, 0, 3, 0, 4, 0, 5];
+        let metadata = read(&TRUECOLOUR, &[
+            (b"sPLT", &[&b"low\0\x08"[..], &[1, 2, 3, 4, 0, 5, 6, 7, 8, 9, 0, 10]].concat()),
+            (b"sPLT", &[&b"high\0\x10"[..], bytes].concat()),
+            (b"sPLT", b"none\0\x08"),
+        ]).unwrap();
+        let entry = |red, green, blue, alpha, frequency| SuggestedEntry { red, green, blue, alpha, frequency };
+        assert_eq!(metadata.suggested_palettes, [
+            SuggestedPalette { name: "low".into(), depth: 8, entries: vec![entry(1, 2, 3, 4, 5), entry(6, 7, 8, 9, 10)] },
+            SuggestedPalette { name: "high".into(), depth: 16, entries: vec![entry(1, 2, 3, 4, 5)] },
+            SuggestedPalette { name: "none".into(), depth: 8, entries: vec![] },
+        ]);
+
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"sPLT", b"name")])), "missing null separator after the name");
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"sPLT", b"name\0")])), "missing sample depth");
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"sPLT", b"name\0\x04")])), "sample depth must be 8 or 16");
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"sPLT", &[&b"name\0\x10"[..], &bytes[..9]].concat())])), "length of the entries must be a multiple of their size");
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"sPLT", b"\0\x08")])), "invalid palette name");
+        assert_eq!(reason(read(&TRUECOLOUR, &[(b"sPLT", b"same\0\x08"), (b"sPLT", b"same\0\x10")])), "two suggested palettes with the same name");
+    }
+}
@@ -0,0 +1,530 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use crate::{encode::*, metadata::*, png::*, Error};
+
+// Lossless means that the decoded pixels don´t change, so images are compared as [R, G, B, A]
+// with the samples scaled to 16 bits, which holds every other format. From there:
+//  * every sample is the same at a smaller bit depth -> that bit depth.
+//  * R = G = B in every pixel -> greyscale.
+//  * every pixel is opaque -> no alpha channel. Also when the transparent ones are all
+//    the same colour and no opaque pixel has it, which is what a tRNS colour key says.
+//  * 256 colours or less -> a palette.
+// Each candidate is encoded with every strategy and the smallest datastream wins.
+
+#[derive(Clone, Debug)]
+pub struct OptimizeOptions {
+    /// Ancillary chunks to remove, like *b"tEXt" or *b"tIME". tRNS and the APNG
+    /// chunks are never removed, the pixels depend on them.
+    pub strip: Vec<[u8; 4]>,
+    /// Whether to try other colour types, bit depths and palettes. Animations keep theirs.
+    pub reduce: bool,
+    /// Ways of encoding to try with every candidate.
+    pub strategies: Vec<EncodeOptions>,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        let strategy = |filter| EncodeOptions { idat_size: i32::MAX as usize, compression: 9, filter };
+        Self {
+            strip: vec![],
+            reduce: true,
+            strategies: vec![strategy(FilterStrategy::Fixed(0)), strategy(FilterStrategy::MinSum), strategy(FilterStrategy::BruteForce)],
+        }
+    }
+}
+
+/// Where a chunk that the encoder doesn´t write goes.
+#[derive(Clone, Copy, PartialEq)]
+enum Place {
+    BeforePlte,
+    /// Also before IDAT.
+    AfterPlte,
+    AfterIdat,
+}
+
+/// An image to encode and the ancillary chunks that go with it.
+struct Candidate {
+    image: PNGImage,
+    chunks: Vec<(Place, Chunk)>,
+}
+
+impl Candidate {
+    fn to_bytes(&self, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+        let encoded = self.image.to_chunks_with(options)?;
+        let idat = encoded.iter().position(|c| c.typ == ChunkType::IDAT).expect("The encoder writes IDAT");
+        let at = |place| self.chunks.iter().filter(move |(p, _)| *p == place).map(|(_, c)| c);
+        // IHDR, PLTE and tRNS, then IDAT and IEND.
+        let chunks = std::iter::once(&encoded[0])
+            .chain(at(Place::BeforePlte))
+            .chain(&encoded[1..idat])
+            .chain(at(Place::AfterPlte))
+            .chain(&encoded[idat..encoded.len() - 1])
+            .chain(at(Place::AfterIdat))
+            .chain(encoded.last());
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        for chunk in chunks {
+            bytes.extend_from_slice(&chunk.to_bytes());
+        }
+        Ok(bytes)
+    }
+}
+
+pub fn optimize(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    optimize_with(bytes, &OptimizeOptions::default())
+}
+
+/// Encodes a PNG datastream again as small as the options let it be, with the same decoded
+/// pixels. The original is returned when nothing was smaller and there was nothing to strip.
+pub fn optimize_with(bytes: &[u8], options: &OptimizeOptions) -> Result<Vec<u8>, Error> {
+    use ChunkType::{acTL, bKGD, fcTL, fdAT, hIST, tRNS, IDAT, IEND, IHDR, PLTE};
+    let chunks = read_chunks(bytes)?;
+    let image = PNGImage::from_chunks(chunks.clone())?;
+    let stripped = |c: &Chunk| c.is_ancillary() && options.strip.contains(&c.name) && !matches!(c.typ, tRNS | acTL | fcTL | fdAT);
+
+    let mut kept = vec![];
+    let mut place = Place::BeforePlte;
+    for chunk in &chunks {
+        match chunk.typ {
+            PLTE => place = Place::AfterPlte,
+            IDAT => place = Place::AfterIdat,
+            IHDR | IEND | tRNS => {}
+            _ if stripped(chunk) => {}
+            // they need the palette before them, in case one is added.
+            bKGD | hIST if place == Place::BeforePlte => kept.push((Place::AfterPlte, chunk.clone())),
+            _ => kept.push((place, chunk.clone())),
+        }
+    }
+    let mut candidates = vec![];
+    // the frames are stored in the format of the image, changing it would need them encoded again.
+    if options.reduce && !chunks.iter().any(|c| c.typ == acTL) {
+        candidates = reduce(&image, &kept);
+    }
+    candidates.push(Candidate { image, chunks: kept });
+
+    let default = [EncodeOptions::default()];
+    let strategies = match options.strategies.is_empty() {
+        true => &default[..],
+        false => &options.strategies[..],
+    };
+    let mut best: Option<Vec<u8>> = None;
+    for candidate in &candidates {
+        for strategy in strategies {
+            let out = candidate.to_bytes(strategy)?;
+            if best.as_ref().is_none_or(|best| out.len() < best.len()) {
+                best = Some(out);
+            }
+        }
+    }
+    let best = best.expect("At least one candidate and strategy");
+    if !chunks.iter().any(stripped) && bytes.len() <= best.len() {
+        return Ok(bytes.to_vec());
+    }
+    Ok(best)
+}
+
+/// A pixel as [R, G, B, A] with 16 bit samples.
+type Pixel = [u16; 4];
+
+/// The header, tRNS and data of the pixels in another format.
+type Reduced = (ImageHeader, Option<Transparency>, Vec<u8>);
+
+/// What a sample of `bit_depth` bits is multiplied by to take it to 16 bits.
+fn scale(bit_depth: u8) -> u16 {
+    (65535 / ((1u32 << bit_depth) - 1)) as u16
+}
+
+/// The smaller formats the pixels of `image` fit in, with their ancillary chunks changed to match.
+fn reduce(image: &PNGImage, kept: &[(Place, Chunk)]) -> Vec<Candidate> {
+    let pixels = pixels(image);
+    let background = background_rgb(image);
+    let mut res = vec![];
+    let (header, transparency, data) = reduce_samples(&image.header, &pixels);
+    // truecolour images keep their suggested palette.
+    let palette = match (image.header.color_type, header.color_type) {
+        (ColorType::Indexed, _) => None,
+        (_, ColorType::Truecolour | ColorType::TruecolourA) => image.palette.clone(),
+        _ => None,
+    };
+    res.push((header, palette, transparency, data, None));
+    if let Some(((header, transparency, data), entries)) = to_palette(&image.header, &pixels, background) {
+        let palette = entries.iter().map(|p| [p[0], p[1], p[2]].map(|x| (x / 257) as u8)).collect();
+        res.push((header, Some(palette), transparency, data, Some(entries)));
+    }
+    let same = |header: &ImageHeader, palette: &Option<Vec<[u8; 3]>>, transparency: &Option<Transparency>| {
+        *header == image.header && *palette == image.palette && *transparency == image.transparency
+    };
+    res.into_iter().filter(|(header, palette, transparency, ..)| !same(header, palette, transparency)).filter_map(|(header, palette, transparency, data, entries)| {
+        let background = match background {
+            Some(rgb) => Some(background_for(rgb, &header, entries.as_deref())?),
+            None => None,
+        };
+        let significant_bits = significant_bits_for(image, &header);
+        let histogram = match &entries {
+            Some(entries) => histogram_for(image, entries),
+            // the suggested palette is still there.
+            None if palette.is_some() => image.metadata.histogram.clone(),
+            None => None,
+        };
+        let chunks = kept.iter().filter_map(|(place, chunk)| {
+            let data = match chunk.typ {
+                ChunkType::sBIT => significant_bits.clone()?,
+                ChunkType::bKGD => match background? {
+                    Background::Greyscale(x) => x.to_be_bytes().to_vec(),
+                    Background::Truecolour(r, g, b) => [r, g, b].iter().flat_map(|x| x.to_be_bytes()).collect(),
+                    Background::Indexed(i) => vec![i],
+                },
+                ChunkType::hIST => histogram.as_ref()?.iter().flat_map(|x| x.to_be_bytes()).collect(),
+                // unknown chunks that are not safe to copy can´t stay when the critical chunks change.
+                ChunkType::AncyllaryChunk if !chunk.is_safe_to_copy() => return None,
+                _ => return Some((*place, chunk.clone())),
+            };
+            Some((*place, Chunk::new(chunk.name, data, 0).expect("Valid name")))
+        }).collect();
+        let bytes_per_pixel = header.bits_per_pixel().div_ceil(8) as u8;
+        let image = PNGImage {
+            header,
+            bytes_per_pixel,
+            palette,
+            transparency,
+            gamma: None,
+            chromaticities: None,
+            srgb: None,
+            text: HashMap::new(),
+            metadata: Metadata::default(),
+            unknown_chunks: vec![],
+            animation: None,
+            data,
+            warnings: vec![],
+        };
+        Some(Candidate { image, chunks })
+    }).collect()
+}
+
+/// Every pixel as it is decoded, with the tRNS chunk applied.
+fn pixels(image: &PNGImage) -> Vec<Pixel> {
+    let header = &image.header;
+    let s = scale(header.bit_depth);
+    let channels = header.color_type.channels();
+    let count = header.width as usize * channels;
+    let mut res = Vec::with_capacity(header.width as usize * header.height as usize);
+    for row in image.rows() {
+        let samples: Vec<u16> = match header.bit_depth {
+            16 => samples_16bit(row),
+            bit_depth => unpack_samples(row, bit_depth, count).into_iter().map(u16::from).collect(),
+        };
+        res.extend(samples.chunks_exact(channels).map(|x| {
+            let key = |transparent: bool| if transparent { 0 } else { 65535 };
+            match (header.color_type, &image.transparency) {
+                (ColorType::Greyscale, trns) => [x[0] * s, x[0] * s, x[0] * s, key(trns == &Some(Transparency::Greyscale(x[0])))],
+                (ColorType::Truecolour, trns) => [x[0] * s, x[1] * s, x[2] * s, key(trns == &Some(Transparency::Truecolour(x[0], x[1], x[2])))],
+                (ColorType::Indexed, trns) => {
+                    let [r, g, b] = image.palette.as_ref().and_then(|p| p.get(x[0] as usize)).copied().unwrap_or([0, 0, 0]);
+                    let a = match trns {
+                        Some(Transparency::Indexed(alpha)) => alpha.get(x[0] as usize).copied().unwrap_or(255),
+                        _ => 255,
+                    };
+                    [r, g, b, a].map(|x| x as u16 * 257)
+                }
+                (ColorType::GreyscaleA, _) => [x[0] * s, x[0] * s, x[0] * s, x[1] * s],
+                (ColorType::TruecolourA, _) => [x[0] * s, x[1] * s, x[2] * s, x[3] * s],
+            }
+        }));
+    }
+    res
+}
+
+/// The smallest greyscale or truecolour format for the pixels.
+fn reduce_samples(original: &ImageHeader, pixels: &[Pixel]) -> Reduced {
+    let opaque = pixels.iter().all(|p| p[3] == 65535);
+    let grey = pixels.iter().all(|p| p[0] == p[1] && p[1] == p[2]);
+    let key = match opaque {
+        true => None,
+        false => colour_key(pixels),
+    };
+    let alpha = !opaque && key.is_none();
+    let (color_type, channels): (_, &[usize]) = match (grey, alpha) {
+        (true, false) => (ColorType::Greyscale, &[0]),
+        (true, true) => (ColorType::GreyscaleA, &[0, 3]),
+        (false, false) => (ColorType::Truecolour, &[0, 1, 2]),
+        (false, true) => (ColorType::TruecolourA, &[0, 1, 2, 3]),
+    };
+    let depths: &[u8] = match color_type {
+        ColorType::Greyscale => &[1, 2, 4, 8, 16],
+        _ => &[8, 16],
+    };
+    // the transparent pixels have the colour of the key, so it fits too.
+    let bit_depth = *depths.iter()
+        .find(|&&d| pixels.iter().all(|p| channels.iter().all(|&c| p[c] % scale(d) == 0)))
+        .expect("16 bits hold any sample");
+    let s = scale(bit_depth);
+    let transparency = key.map(|[r, g, b]| match grey {
+        true => Transparency::Greyscale(r / s),
+        false => Transparency::Truecolour(r / s, g / s, b / s),
+    });
+    let header = ImageHeader { bit_depth, color_type, ..*original };
+    let data = pixels.chunks(header.width as usize)
+        .flat_map(|row| pack(row.iter().flat_map(|p| channels.iter().map(|&c| p[c] / s)), bit_depth))
+        .collect();
+    (header, transparency, data)
+}
+
+/// The colour of the transparent pixels, when all of them are fully transparent and
+/// have the same colour, and no opaque pixel has it.
+fn colour_key(pixels: &[Pixel]) -> Option<[u16; 3]> {
+    let rgb = |p: &Pixel| [p[0], p[1], p[2]];
+    let key = rgb(pixels.iter().find(|p| p[3] == 0)?);
+    let fits = pixels.iter().all(|p| match p[3] {
+        0 => rgb(p) == key,
+        65535 => rgb(p) != key,
+        _ => false,
+    });
+    fits.then_some(key)
+}
+
+/// The pixels as an indexed-colour image, with its palette entries. None when there are more than
+/// 256 colours, counting the background, or samples that don´t fit in the 8 bits of the palette.
+fn to_palette(original: &ImageHeader, pixels: &[Pixel], background: Option<[u16; 3]>) -> Option<(Reduced, Vec<Pixel>)> {
+    if pixels.iter().flatten().chain(background.iter().flatten()).any(|&x| x % 257 != 0) {
+        return None;
+    }
+    let mut counts: HashMap<Pixel, usize> = HashMap::new();
+    for pixel in pixels {
+        *counts.entry(*pixel).or_default() += 1;
+        if counts.len() > 256 {
+            return None;
+        }
+    }
+    // transparent entries first so the tRNS chunk is shorter, then the most used ones.
+    let mut entries: Vec<(Pixel, usize)> = counts.into_iter().collect();
+    entries.sort_by_key(|&(p, count)| (p[3] == 65535, Reverse(count), p));
+    let mut entries: Vec<Pixel> = entries.into_iter().map(|(p, _)| p).collect();
+    if let Some([r, g, b]) = background {
+        if !entries.iter().any(|p| p[..3] == [r, g, b]) {
+            entries.push([r, g, b, 65535]);
+        }
+    }
+    if entries.len() > 256 {
+        return None;
+    }
+    let bit_depth = match entries.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let index: HashMap<Pixel, u16> = entries.iter().enumerate().map(|(i, p)| (*p, i as u16)).collect();
+    let transparency = entries.iter().rposition(|p| p[3] != 65535)
+        .map(|last| Transparency::Indexed(entries[..=last].iter().map(|p| (p[3] / 257) as u8).collect()));
+    let header = ImageHeader { bit_depth, color_type: ColorType::Indexed, ..*original };
+    let data = pixels.chunks(header.width as usize).flat_map(|row| pack(row.iter().map(|p| index[p]), bit_depth)).collect();
+    Some(((header, transparency, data), entries))
+}
+
+/// One scanline from its samples, packing the ones smaller than a byte.
+fn pack(samples: impl Iterator<Item = u16>, bit_depth: u8) -> Vec<u8> {
+    let mut row = vec![];
+    match bit_depth {
+        16 => samples.for_each(|x| row.extend_from_slice(&x.to_be_bytes())),
+        8 => row.extend(samples.map(|x| x as u8)),
+        _ => {
+            let per_byte = 8 / bit_depth as usize;
+            for (i, x) in samples.enumerate() {
+                if i % per_byte == 0 {
+                    row.push(0);
+                }
+                *row.last_mut().expect("Just added") |= (x as u8) << (8 - bit_depth as usize * (i % per_byte + 1));
+            }
+        }
+    }
+    row
+}
+
+/// The bKGD colour with 16 bit samples.
+fn background_rgb(image: &PNGImage) -> Option<[u16; 3]> {
+    let s = scale(image.header.bit_depth);
+    match image.metadata.background? {
+        Background::Greyscale(x) => Some([x * s; 3]),
+        Background::Truecolour(r, g, b) => Some([r * s, g * s, b * s]),
+        Background::Indexed(i) => image.palette.as_ref()?.get(i as usize).map(|e| e.map(|x| x as u16 * 257)),
+    }
+}
+
+/// The background in the format of `header`, None when it doesn´t fit in it.
+fn background_for([r, g, b]: [u16; 3], header: &ImageHeader, entries: Option<&[Pixel]>) -> Option<Background> {
+    let s = scale(header.bit_depth);
+    match header.color_type {
+        ColorType::Greyscale | ColorType::GreyscaleA => (r == g && g == b && r % s == 0).then(|| Background::Greyscale(r / s)),
+        ColorType::Truecolour | ColorType::TruecolourA => [r, g, b].iter().all(|x| x % s == 0).then(|| Background::Truecolour(r / s, g / s, b / s)),
+        ColorType::Indexed => entries?.iter().position(|p| p[..3] == [r, g, b]).map(|i| Background::Indexed(i as u8)),
+    }
+}
+
+/// The sBIT values in the channels of `header`, never more than its bit depth.
+fn significant_bits_for(image: &PNGImage, header: &ImageHeader) -> Option<Vec<u8>> {
+    let bits = image.metadata.significant_bits.as_ref()?;
+    // [R, G, B] and the alpha, when the original image had it.
+    let (rgb, alpha) = match image.header.color_type {
+        ColorType::Greyscale => ([bits[0]; 3], None),
+        ColorType::GreyscaleA => ([bits[0]; 3], Some(bits[1])),
+        ColorType::Truecolour | ColorType::Indexed => ([bits[0], bits[1], bits[2]], None),
+        ColorType::TruecolourA => ([bits[0], bits[1], bits[2]], Some(bits[3])),
+    };
+    let max = match header.color_type {
+        ColorType::Indexed => 8,
+        _ => header.bit_depth,
+    };
+    let grey = *rgb.iter().max().expect("Three channels");
+    let alpha = alpha.unwrap_or(max);
+    let bits = match header.color_type {
+        ColorType::Greyscale => vec![grey],
+        ColorType::GreyscaleA => vec![grey, alpha],
+        ColorType::Truecolour | ColorType::Indexed => rgb.to_vec(),
+        ColorType::TruecolourA => vec![rgb[0], rgb[1], rgb[2], alpha],
+    };
+    Some(bits.into_iter().map(|x| x.min(max)).collect())
+}
+
+/// The hIST of an indexed-colour image for the new palette, the frequencies of entries that
+/// became the same one are added.
+fn histogram_for(image: &PNGImage, entries: &[Pixel]) -> Option<Vec<u16>> {
+    let histogram = image.metadata.histogram.as_ref()?;
+    if image.header.color_type != ColorType::Indexed {
+        return None;
+    }
+    let alpha = match &image.transparency {
+        Some(Transparency::Indexed(alpha)) => &alpha[..],
+        _ => &[],
+    };
+    let mut res = vec![0u16; entries.len()];
+    for (i, (&frequency, entry)) in histogram.iter().zip(image.palette.as_ref()?).enumerate() {
+        let pixel = [entry[0], entry[1], entry[2], alpha.get(i).copied().unwrap_or(255)].map(|x| x as u16 * 257);
+        if let Some(j) = entries.iter().position(|p| *p == pixel) {
+            res[j] = res[j].saturating_add(frequency);
+        }
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::noise;
+
+    fn image(header: ImageHeader, palette: Option<Vec<[u8; 3]>>, transparency: Option<Transparency>, data: Vec<u8>) -> PNGImage {
+        PNGImage {
+            header,
+            bytes_per_pixel: header.bits_per_pixel().div_ceil(8) as u8,
+            palette,
+            transparency,
+            gamma: None,
+            chromaticities: None,
+            srgb: None,
+            text: HashMap::new(),
+            metadata: Metadata::default(),
+            unknown_chunks: vec![],
+            animation: None,
+            data,
+            warnings: vec![],
+        }
+    }
+
+    // the datastream of `image`, with `extra` chunks right before IDAT, or PLTE for sBIT.
+    fn encode(image: &PNGImage, extra: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut chunks = image.to_chunks_with(&EncodeOptions::default()).unwrap();
+        for (name, data) in extra {
+            let at = match *name {
+                b"sBIT" => 1,
+                _ => chunks.iter().position(|c| c.typ == ChunkType::IDAT).unwrap(),
+            };
+            chunks.insert(at, Chunk::new(**name, data.clone(), 0).unwrap());
+        }
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        chunks.iter().for_each(|c| bytes.extend_from_slice(&c.to_bytes()));
+        bytes
+    }
+
+    // the optimized image, after checking that it has the same pixels and is not larger.
+    fn optimized(bytes: &[u8]) -> PNGImage {
+        let out = optimize(bytes).unwrap();
+        assert!(out.len() <= bytes.len());
+        let before = PNGImage::from_bytes(bytes).unwrap();
+        let after = PNGImage::from_bytes(&out).unwrap();
+        assert_eq!(after.to_rgba8(), before.to_rgba8());
+        after
+    }
+
+    fn header(width: u32, height: u32, bit_depth: u8, color_type: ColorType) -> ImageHeader {
+        ImageHeader { width, height, bit_depth, color_type, interlace: Interlace::None }
+    }
+
+    #[test]
+    fn reduce() {
+        // 16 bit greyscale with samples that fit in 4 bits.
+        let data = (0..256u16).flat_map(|i| (i % 16 * 4369).to_be_bytes()).collect();
+        let bytes = encode(&image(header(16, 16, 16, ColorType::Greyscale), None, None, data), &[(b"sBIT", vec![12])]);
+        let out = optimized(&bytes);
+        assert_eq!((out.header.color_type, out.header.bit_depth), (ColorType::Greyscale, 4));
+        assert_eq!(out.metadata.significant_bits, Some(vec![4]));
+
+        // truecolour with R = G = B and an alpha channel that is always opaque.
+        let data = noise(32 * 32, 2, 256).into_iter().flat_map(|x| [x, x, x, 255]).collect();
+        let out = optimized(&encode(&image(header(32, 32, 8, ColorType::TruecolourA), None, None, data), &[]));
+        assert_eq!((out.header.color_type, out.header.bit_depth), (ColorType::Greyscale, 8));
+
+        // noise already in its smallest format stays the same.
+        let data = noise(16 * 16 * 3, 5, 256);
+        let bytes = encode(&image(header(16, 16, 8, ColorType::Truecolour), None, None, data), &[]);
+        assert_eq!(optimized(&bytes).header, PNGImage::from_bytes(&bytes).unwrap().header);
+    }
+
+    #[test]
+    fn to_palette() {
+        let colours = [[200, 10, 30, 255], [0, 0, 0, 0], [10, 200, 30, 128]];
+        let data = noise(32 * 32, 3, 3).into_iter().flat_map(|i| colours[i as usize]).collect();
+        let out = optimized(&encode(&image(header(32, 32, 8, ColorType::TruecolourA), None, None, data), &[]));
+        assert_eq!((out.header.color_type, out.header.bit_depth), (ColorType::Indexed, 2));
+        assert_eq!(out.palette.as_ref().map(Vec::len), Some(3));
+        // the transparent entries go first, so the tRNS chunk can stop before the opaque one.
+        assert!(matches!(out.transparency, Some(Transparency::Indexed(ref alpha)) if alpha.len() == 2));
+    }
+
+    #[test]
+    fn colour_key() {
+        // more colours than a palette holds, and the transparent pixels are all the same.
+        let data = (0..1024u32).flat_map(|i| match i % 9 {
+            0 => [1, 2, 3, 0],
+            _ => [i as u8, (i >> 2) as u8, 200, 255],
+        }).collect();
+        let out = optimized(&encode(&image(header(32, 32, 8, ColorType::TruecolourA), None, None, data), &[]));
+        assert_eq!((out.header.color_type, out.header.bit_depth), (ColorType::Truecolour, 8));
+        assert_eq!(out.transparency, Some(Transparency::Truecolour(1, 2, 3)));
+
+        let pixels = [[257, 514, 771, 0], [0, 0, 0, 65535]];
+        assert_eq!(super::colour_key(&pixels), Some([257, 514, 771]));
+        // an opaque pixel with the colour of the key.
+        assert_eq!(super::colour_key(&[pixels[0], [257, 514, 771, 65535]]), None);
+        // transparent pixels of different colours, or partly transparent ones.
+        assert_eq!(super::colour_key(&[pixels[0], [0, 0, 0, 0]]), None);
+        assert_eq!(super::colour_key(&[pixels[0], [0, 0, 0, 100]]), None);
+    }
+
+    #[test]
+    fn ancillary_chunks_remapped() {
+        // entries 0 and 2 are the same colour, so they become one.
+        let palette = vec![[10, 20, 30], [40, 50, 60], [10, 20, 30], [70, 80, 90], [1, 1, 1]];
+        let data = noise(32 * 32, 4, 4);
+        let extra = [(b"sBIT", vec![5, 6, 5]), (b"bKGD", vec![4]), (b"hIST", [1u16, 2, 3, 4, 0].iter().flat_map(|x| x.to_be_bytes()).collect())];
+        let out = optimized(&encode(&image(header(32, 32, 8, ColorType::Indexed), Some(palette), None, data), &extra));
+        assert_eq!((out.header.color_type, out.header.bit_depth), (ColorType::Indexed, 2));
+        let palette = out.palette.unwrap();
+        assert_eq!(palette.len(), 4);
+        let index = |rgb: [u8; 3]| palette.iter().position(|&p| p == rgb).unwrap();
+        assert_eq!(out.metadata.background, Some(Background::Indexed(index([1, 1, 1]) as u8)));
+        assert_eq!(out.metadata.significant_bits, Some(vec![5, 6, 5]));
+        let histogram = out.metadata.histogram.unwrap();
+        assert_eq!(histogram[index([10, 20, 30])], 4);
+        assert_eq!(histogram[index([40, 50, 60])], 2);
+        assert_eq!(histogram[index([70, 80, 90])], 4);
+        assert_eq!(histogram[index([1, 1, 1])], 0);
+    }
+}
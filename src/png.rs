@@ -1,203 +1,1434 @@
-#![allow(non_snake_case)]
-use std::fs;
-
-use crate::zlib;
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum ChunkType {
-    IHDR, //image header, which is the first chunk in a PNG datastream.
-    //PLTE, //palette table associated with indexed PNG images.
-    IDAT, //image data chunks.
-    IEND, //image trailer, which is the last chunk in a PNG datastream.
-    AncyllaryChunk, //the ones we will ignore for simplicity purposes.
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct Chunk {
-    pub typ: ChunkType,
-    pub data: Vec<u8>,
-}
-
-//      VALID IMAGE TYPE - COLOUR TYPE
-// ┌───────────────────────┬──────────────┐
-// │    PNG image type     │ Colour type  │
-// ├───────────────────────┼──────────────┤
-// │ Greyscale             │      0       │ [0-255]
-// │ Truecolour            │      2       │ [R, G, B] <- each one between 0-255
-// │ Indexed-colour        │      3       │ <- Won´t be supported.
-// │ Greyscale with alpha  │      4       │ [[0-255], A]
-// │ Truecolour with alpha │      6       │ [R, G, B, A] <- each one between 0-255
-// └───────────────────────┴──────────────┘
-// images of more than 8 bit depth will be truncated
-
-impl Chunk {
-    pub fn from_slice(bytes: &[u8]) -> Chunk {
-        use ChunkType::*;
-        let length = u32::from_be_bytes(bytes[..4].try_into().expect("Valid length"));
-        let typ = match &bytes[4..8] {
-            b"IHDR" => IHDR,
-            //b"PLTE" => PLTE,
-            b"IDAT" => IDAT,
-            b"IEND" => IEND,
-            _ => AncyllaryChunk,
-        };
-        let data = bytes[8.. 8 + length as usize].to_vec();
-        // TODO: CHECKSUM
-        Chunk { typ, data }
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub struct ImageHeader {
-    pub width: u32,
-    pub height: u32,
-    pub bit_depth: u8,
-    pub color_type: ColorType,
-}
-
-impl ImageHeader {
-    pub fn from_chunk(header: Chunk) -> Self{
-        if header.typ != ChunkType::IHDR {
-            panic!("ChunkType provided: {:?}, expected IHDR", header.typ);
-        }
-        let data = header.data;
-        let width = u32::from_be_bytes(data[..4].try_into().expect("Valid length"));
-        let height = u32::from_be_bytes(data[4..8].try_into().expect("Valid length"));
-        let bit_depth = data[8];
-        use ColorType::*;
-        let color_type = match data[9] {
-            0 => Greyscale,
-            2 => Truecolour,
-            4 => GreyscaleA,
-            6 => TruecolourA,
-            invalid => panic!("Invalid Colour type: {}", invalid),
-        };
-        Self {
-            width,
-            height,
-            bit_depth,
-            color_type,
-        }
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum ColorType {
-    Greyscale,
-    Truecolour,
-    GreyscaleA,
-    TruecolourA,
-}
-
-pub struct PNGImage {
-    pub header: ImageHeader,
-    pub bytes_per_pixel: u8,
-    pub data: Vec<u8>,
-}
-
-impl PNGImage {
-    pub fn from_path(path: &str) -> Self {
-        let f = fs::read(path).unwrap();
-
-        match &f[..8] {
-            b"\x89PNG\r\n\x1a\n" => println!("Header Ok."),
-            invalido => panic!("invalid header : {:x?}\n", invalido),
-        }
-        let mut chunks = vec![];
-        let mut i = 8;
-        while i != f.len(){
-            let chunk = Chunk::from_slice(&f[i..]);
-            i += chunk.data.len() + 12; // 12 = (length) + (type) + (CRC) = 4 + 4 + 4
-            chunks.push(chunk);
-        }
-        let image = PNGImage::from_chunks(chunks);
-
-        return image;
-    }
-    pub fn from_chunks(chunks: Vec<Chunk>) -> Self {
-        use ChunkType::IDAT;
-        let header = ImageHeader::from_chunk(chunks[0].clone());
-
-        let bytes_per_pixel = match header.color_type {
-            ColorType::Greyscale => 1,
-            ColorType::GreyscaleA => 2,
-            ColorType::Truecolour => 3,
-            ColorType::TruecolourA => 4,
-        } * ((header.bit_depth + 7) / 8);
-        let image = Self { header, bytes_per_pixel, data: vec![] };
-
-        let mut compressed_data = vec![];
-        for mut chunk in chunks {
-            if chunk.typ == IDAT {
-                compressed_data.append(&mut chunk.data);
-            }
-        }
-        println!("INFLATING..");
-        let decompressed_data = zlib::inflate(&compressed_data);
-        //let decompressed_data = miniz_oxide::inflate::decompress_to_vec_zlib(&compressed_data).unwrap();
-
-        //println!("UNFILTERING..");
-        let data = image.unfilter(decompressed_data);
-
-        Self { header, bytes_per_pixel, data }
-    }
-
-    fn PaethPredictor(a: i32, b: i32, c: i32) -> i32 {
-        let p = a + b - c;
-        let pa = i32::abs(p - a);
-        let pb = i32::abs(p - b);
-        let pc = i32::abs(p - c);
-        if pa <= pb && pa <= pc {
-            return a
-        }
-        else if pb <= pc {
-            return b
-        } else {
-            return c
-        }
-    }
-
-    fn unfilter(self, decompressed_data: Vec<u8>) -> Vec<u8> {
-        let rows = self.header.height as usize;
-        let cols = self.header.width as usize * self.bytes_per_pixel as usize;
-
-        //┌───┬──────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────┐
-        //│ x │	the byte being filtered;					                                                                                                             │
-        //│ a │	the byte corresponding to x in the pixel immediately before the pixel containing x (or the byte immediately before x, when the bit depth is less than 8);│
-        //│ b │	the byte corresponding to x in the previous scanline;                                                                                                    │
-        //│ c │	the byte corresponding to b in the pixel immediately before the pixel containing b (or the byte immediately before b, when the bit depth is less than 8).│
-        //└───┴──────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────┘
-        let mut data = Vec::with_capacity(rows * cols);//vec![0u8; ];
-        for i in 0..rows {
-            let typ = decompressed_data[i * (cols + 1)];
-            for j in 0..cols {
-                let x = decompressed_data[i * (cols + 1) + j + 1] as u16;
-                let xi = i * cols + j;
-                let (left, up) = (j >= self.bytes_per_pixel as usize,  i  > 0);
-                let a = match left {
-                    true => data[xi - self.bytes_per_pixel as usize] as u16,
-                    false => 0,
-                };
-
-                let b = match up {
-                    true =>  data[xi - cols] as u16,
-                    false => 0,
-                    
-                };
-                let c = match left && up {
-                    true => data[xi - cols - self.bytes_per_pixel as usize] as u16,
-                    false => 0,
-                };
-                /*data[xi] = */data.push(match typ {
-                    0 => x as u8,
-                    1 => ((x + a) & 255) as u8,
-                    2 => ((x + b) & 255) as u8,
-                    3 => ((x + ((a as usize + b as usize) / 2) as u16 ) & 255) as u8,
-                    4 => ((x + (Self::PaethPredictor(a as i32, b as i32 , c as i32)) as u16) & 255) as u8, //x.wrapping_add(),
-                    invalid => panic!("invalid filter method, type: {}", invalid),
-                });
-            }
-        }
-        return data;
-    }
-}
+#![allow(non_snake_case)]
+use std::{collections::HashMap, fs::File, io::Read, time::Duration};
+
+use crate::{color::*, metadata::*, text::*, validate::*, zlib::{self, InflateOptions}, Error};
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkType {
+    IHDR, //image header, which is the first chunk in a PNG datastream.
+    PLTE, //palette table associated with indexed PNG images.
+    IDAT, //image data chunks.
+    IEND, //image trailer, which is the last chunk in a PNG datastream.
+    tRNS, //transparency, alpha of the palette entries or the colour that is fully transparent.
+    gAMA, //gamma the samples were encoded with.
+    cHRM, //chromaticities of the primaries and the white point.
+    sRGB, //the samples are already in the sRGB colour space.
+    tEXt, //keyword and text in Latin-1.
+    zTXt, //keyword and compressed text in Latin-1.
+    iTXt, //keyword and text in UTF-8, maybe compressed, with its language.
+    tIME, //time of the last modification.
+    pHYs, //pixels per unit, or their aspect ratio.
+    sBIT, //significant bits of each channel in the original image.
+    bKGD, //background colour.
+    hIST, //how often each palette entry is used.
+    sPLT, //suggested palette.
+    eXIf, //Exif profile.
+    acTL, //animation control, the number of frames and loops.
+    fcTL, //frame control, the size, position and delay of a frame.
+    fdAT, //frame data, like IDAT for the frames after the first one.
+    AncyllaryChunk, //the ones we don´t know, `Chunk::name` tells which one it is.
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    pub typ: ChunkType,
+    /// The four letters of the type as stored, also for the chunks `typ` doesn´t know.
+    pub name: [u8; 4],
+    pub data: Vec<u8>,
+    pub crc: u32, // the one stored after the data.
+    pub offset: usize, // position of the length field in the datastream.
+    pub(crate) computed_crc: u32,
+}
+
+// CRC-32 of ISO 3309 (the same one zip and ethernet use), x^32+x^26+x^23+x^22+x^16+x^12+x^11+x^10+x^8+x^7+x^5+x^4+x^2+x+1,
+// computed over the chunk type and data, not the length. Table from the sample code on https://www.w3.org/TR/png/#D-CRCAppendix.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+};
+
+/// Continues a CRC-32 with more bytes, start with 0.
+pub fn update_crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut c = crc ^ 0xffffffff;
+    for &byte in bytes {
+        c = CRC_TABLE[((c ^ byte as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    c ^ 0xffffffff
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    update_crc32(0, bytes)
+}
+
+//      VALID IMAGE TYPE - COLOUR TYPE
+// ┌───────────────────────┬──────────────┐
+// │    PNG image type     │ Colour type  │
+// ├───────────────────────┼──────────────┤
+// │ Greyscale             │      0       │ [0-255]
+// │ Truecolour            │      2       │ [R, G, B] <- each one between 0-255
+// │ Indexed-colour        │      3       │ [index] <- into the PLTE entries, each one [R, G, B]
+// │ Greyscale with alpha  │      4       │ [[0-255], A]
+// │ Truecolour with alpha │      6       │ [R, G, B, A] <- each one between 0-255
+// └───────────────────────┴──────────────┘
+// images of 16 bit depth store each sample in two bytes (big endian), and the ones
+// of less than 8 bits (1, 2 or 4) are packed in each byte, leftmost pixel first.
+
+impl ChunkType {
+    /// `offset` is only used for the errors of invalid names and unknown critical chunks.
+    pub fn from_name(name: [u8; 4], offset: usize) -> Result<ChunkType, Error> {
+        use ChunkType::*;
+        if !name.iter().all(u8::is_ascii_alphabetic) {
+            return Err(Error::InvalidChunkName { name, offset });
+        }
+        Ok(match &name {
+            b"IHDR" => IHDR,
+            b"PLTE" => PLTE,
+            b"IDAT" => IDAT,
+            b"IEND" => IEND,
+            b"tRNS" => tRNS,
+            b"gAMA" => gAMA,
+            b"cHRM" => cHRM,
+            b"sRGB" => sRGB,
+            b"tEXt" => tEXt,
+            b"zTXt" => zTXt,
+            b"iTXt" => iTXt,
+            b"tIME" => tIME,
+            b"pHYs" => pHYs,
+            b"sBIT" => sBIT,
+            b"bKGD" => bKGD,
+            b"hIST" => hIST,
+            b"sPLT" => sPLT,
+            b"eXIf" => eXIf,
+            b"acTL" => acTL,
+            b"fcTL" => fcTL,
+            b"fdAT" => fdAT,
+            // unknown ancillary chunks can be skipped, unknown critical ones can´t.
+            _ if name[0] & 0x20 == 0 => return Err(Error::UnknownCriticalChunk { typ: name, offset }),
+            _ => AncyllaryChunk,
+        })
+    }
+}
+
+impl Chunk {
+    pub fn from_slice(bytes: &[u8]) -> Result<Chunk, Error> {
+        Self::read_at(bytes, 0)
+    }
+
+    /// Reads the chunk starting at `offset`, errors point to their position in `bytes`.
+    pub fn read_at(bytes: &[u8], offset: usize) -> Result<Chunk, Error> {
+        let bytes = &bytes[offset..];
+        if bytes.len() < 12 {
+            return Err(Error::TruncatedChunk { offset });
+        }
+        let length = u32::from_be_bytes(bytes[..4].try_into().expect("Valid length")) as usize;
+        if bytes.len() - 12 < length {
+            return Err(Error::TruncatedChunk { offset });
+        }
+        let name = bytes[4..8].try_into().expect("Valid length");
+        let typ = ChunkType::from_name(name, offset)?;
+        let data = bytes[8.. 8 + length].to_vec();
+        let crc = u32::from_be_bytes(bytes[8 + length..12 + length].try_into().expect("Valid length"));
+        let computed_crc = crc32(&bytes[4..8 + length]);
+        Ok(Chunk { typ, name, data, crc, offset, computed_crc })
+    }
+
+    // The case of each letter of the name is a property (bit 5, set when the letter is lowercase):
+    //  * 1st -> ancillary, decoders that don´t know the chunk can skip it. Uppercase ones are critical.
+    //  * 2nd -> private, not defined by the spec nor registered.
+    //  * 3rd -> reserved, uppercase in every chunk for now.
+    //  * 4th -> safe to copy, editors that don´t know it can keep it even when they change the image.
+    pub fn is_ancillary(&self) -> bool {
+        self.name[0] & 0x20 != 0
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.name[1] & 0x20 != 0
+    }
+
+    /// Whether the reserved bit is set, which makes the chunk unknown to every decoder.
+    pub fn reserved_bit(&self) -> bool {
+        self.name[2] & 0x20 != 0
+    }
+
+    pub fn is_safe_to_copy(&self) -> bool {
+        self.name[3] & 0x20 != 0
+    }
+
+    /// The type as text, like "tEXt".
+    pub fn name_str(&self) -> &str {
+        std::str::from_utf8(&self.name).expect("Names are ASCII letters")
+    }
+
+    /// CRC of the chunk type and data as they were read.
+    pub fn computed_crc(&self) -> u32 {
+        self.computed_crc
+    }
+
+    /// Whether the stored CRC matches the one of the chunk type and data.
+    pub fn crc_ok(&self) -> bool {
+        self.crc == self.computed_crc
+    }
+
+    /// A chunk to be written at `offset`, with the CRC of its name and data.
+    pub fn new(name: [u8; 4], data: Vec<u8>, offset: usize) -> Result<Chunk, Error> {
+        let typ = ChunkType::from_name(name, offset)?;
+        let crc = update_crc32(crc32(&name), &data);
+        Ok(Chunk { typ, name, data, crc, offset, computed_crc: crc })
+    }
+
+    /// The chunk as it is stored: length, type, data and CRC.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.data.len() + 12);
+        bytes.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.name);
+        bytes.extend_from_slice(&self.data);
+        bytes.extend_from_slice(&self.crc.to_be_bytes());
+        bytes
+    }
+}
+
+/// Splits a datastream into its chunks, after checking the PNG signature. It ends at
+/// IEND, whatever comes after it is ignored (`validate` reports it).
+pub fn read_chunks(bytes: &[u8]) -> Result<Vec<Chunk>, Error> {
+    if bytes.get(..8) != Some(b"\x89PNG\r\n\x1a\n") {
+        return Err(Error::BadSignature);
+    }
+    let mut chunks = vec![];
+    let mut i = 8;
+    while i != bytes.len(){
+        let chunk = Chunk::read_at(bytes, i)?;
+        i += chunk.data.len() + 12; // 12 = (length) + (type) + (CRC) = 4 + 4 + 4
+        let end = chunk.typ == ChunkType::IEND;
+        chunks.push(chunk);
+        if end {
+            break;
+        }
+    }
+    Ok(chunks)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum CrcCheck {
+    /// A chunk with a wrong CRC stops the decoding with `Error::CrcMismatch`.
+    #[default]
+    Strict,
+    /// Chunks with a wrong CRC are used anyway and reported in `PNGImage::warnings`.
+    Lenient,
+}
+
+/// How big an image can be before decoding it stops with `Error::LimitExceeded`, so that
+/// a small file can´t make the decoder allocate gigabytes.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Width times height.
+    pub max_pixels: u64,
+    /// Bytes of the inflated data of each zlib stream, the image data or the one of a frame.
+    pub max_decompressed: usize,
+    /// Bytes of the data of each chunk.
+    pub max_chunk_length: usize,
+    /// Chunks in the datastream, IEND included.
+    pub max_chunks: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_width: 1 << 20,
+            max_height: 1 << 20,
+            max_pixels: 1 << 28,
+            max_decompressed: 1 << 29,
+            max_chunk_length: 1 << 28,
+            max_chunks: 1 << 20,
+        }
+    }
+}
+
+impl Limits {
+    /// `index` is the position of the chunk in the datastream, from 0.
+    pub(crate) fn check_chunk(&self, index: usize, length: usize, offset: usize) -> Result<(), Error> {
+        let limit = match () {
+            _ if index >= self.max_chunks => "too many chunks",
+            _ if length > self.max_chunk_length => "chunk too long",
+            _ => return Ok(()),
+        };
+        Err(Error::LimitExceeded { limit, offset })
+    }
+
+    /// `offset` is the one of the IHDR chunk.
+    pub(crate) fn check_header(&self, header: &ImageHeader, offset: usize) -> Result<(), Error> {
+        let limit = match () {
+            _ if header.width > self.max_width => "image too wide",
+            _ if header.height > self.max_height => "image too tall",
+            _ if header.width as u64 * header.height as u64 > self.max_pixels => "too many pixels",
+            _ => return Ok(()),
+        };
+        Err(Error::LimitExceeded { limit, offset })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DecodeOptions {
+    pub crc_check: CrcCheck,
+    pub limits: Limits,
+    /// Doesn´t verify the Adler-32 at the end of the zlib streams, a bit faster.
+    pub skip_adler32: bool,
+    /// Turns the tRNS chunk into an alpha channel, see `PNGImage::expand_trns`.
+    pub expand_trns: bool,
+    /// Converts the samples to sRGB with the gAMA and cHRM chunks, see `PNGImage::convert_to_srgb`.
+    pub convert_to_srgb: bool,
+}
+
+impl DecodeOptions {
+    pub(crate) fn inflate_options(&self) -> InflateOptions {
+        InflateOptions { max_size: self.limits.max_decompressed, verify_checksum: !self.skip_adler32 }
+    }
+}
+
+/// Problems found while decoding that did not stop it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    CrcMismatch { index: usize, offset: usize, typ: ChunkType, stored: u32, computed: u32 },
+    /// An ancillary chunk that breaks the spec, it was ignored.
+    InvalidChunk { index: usize, offset: usize, typ: ChunkType, reason: &'static str },
+}
+
+impl Warning {
+    /// For an ancillary chunk that could not be used because of `e`.
+    pub(crate) fn ignored(index: usize, chunk: &Chunk, e: Error) -> Warning {
+        let reason = match e {
+            Error::InvalidChunk { reason, .. } | Error::DeflateError { reason, .. } | Error::LimitExceeded { limit: reason, .. } => reason,
+            _ => "invalid data",
+        };
+        Warning::InvalidChunk { index, offset: chunk.offset, typ: chunk.typ, reason }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageHeader {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub color_type: ColorType,
+    pub interlace: Interlace,
+}
+
+impl ImageHeader {
+    pub fn from_chunk(header: Chunk) -> Result<Self, Error> {
+        let invalid = |reason| Error::InvalidChunk { typ: header.typ, offset: header.offset, reason };
+        if header.typ != ChunkType::IHDR {
+            return Err(invalid("expected IHDR"));
+        }
+        let data = &header.data;
+        if data.len() != 13 {
+            return Err(invalid("length must be 13"));
+        }
+        let width = u32::from_be_bytes(data[..4].try_into().expect("Valid length"));
+        let height = u32::from_be_bytes(data[4..8].try_into().expect("Valid length"));
+        if width == 0 || height == 0 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+            return Err(invalid("width and height must be between 1 and 2^31 - 1"));
+        }
+        let bit_depth = data[8];
+        use ColorType::*;
+        let color_type = match data[9] {
+            0 => Greyscale,
+            2 => Truecolour,
+            3 => Indexed,
+            4 => GreyscaleA,
+            6 => TruecolourA,
+            _ => return Err(invalid("invalid colour type")),
+        };
+        let allowed_depths: &[u8] = match color_type {
+            Greyscale => &[1, 2, 4, 8, 16],
+            Indexed => &[1, 2, 4, 8],
+            Truecolour | GreyscaleA | TruecolourA => &[8, 16],
+        };
+        if !allowed_depths.contains(&bit_depth) {
+            return Err(invalid("invalid bit depth for the colour type"));
+        }
+        if data[10] != 0 || data[11] != 0 {
+            return Err(invalid("unknown compression or filter method"));
+        }
+        let interlace = match data[12] {
+            0 => Interlace::None,
+            1 => Interlace::Adam7,
+            _ => return Err(invalid("invalid interlace method")),
+        };
+        Ok(Self {
+            width,
+            height,
+            bit_depth,
+            color_type,
+            interlace,
+        })
+    }
+
+    pub fn bits_per_pixel(&self) -> usize {
+        self.color_type.channels() * self.bit_depth as usize
+    }
+
+    /// Bytes of a scanline without its filter type byte, samples smaller
+    /// than a byte are packed together so the last one may be padded.
+    pub fn row_bytes(&self) -> usize {
+        (self.width as usize * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    /// Size of the inflated image data, the scanlines of every pass with their filter type bytes.
+    /// `Error::LimitExceeded` when it doesn´t fit in a usize, at the offset of IHDR, always 8.
+    pub fn data_size(&self) -> Result<usize, Error> {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let size = |w: usize, h: usize| w.checked_mul(self.bits_per_pixel())?.div_ceil(8).checked_add(1)?.checked_mul(h);
+        let total = match self.interlace {
+            Interlace::None => size(width, height),
+            Interlace::Adam7 => (0..7)
+                .map(|pass| adam7_pass_size(width, height, pass))
+                .filter(|&(w, h)| w > 0 && h > 0)
+                .try_fold(0usize, |total, (w, h)| total.checked_add(size(w, h)?)),
+        };
+        total.ok_or(Error::LimitExceeded { limit: "image data too big", offset: 8 })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interlace {
+    None,
+    Adam7,
+}
+
+// Adam7 passes over each 8x8 block of the image:
+//   1 6 4 6 2 6 4 6
+//   7 7 7 7 7 7 7 7
+//   5 6 5 6 5 6 5 6
+//   7 7 7 7 7 7 7 7
+//   3 6 4 6 3 6 4 6
+//   7 7 7 7 7 7 7 7
+//   5 6 5 6 5 6 5 6
+//   7 7 7 7 7 7 7 7
+// (starting column, starting row, column increment, row increment) of every pass.
+pub(crate) const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Width and height of the reduced image of an Adam7 pass, passes without
+/// pixels are not stored at all, not even their filter type bytes.
+pub(crate) fn adam7_pass_size(width: usize, height: usize, pass: usize) -> (usize, usize) {
+    let (x0, y0, dx, dy) = ADAM7[pass];
+    let w = if width > x0 { (width - x0).div_ceil(dx) } else { 0 };
+    let h = if height > y0 { (height - y0).div_ceil(dy) } else { 0 };
+    (w, h)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorType {
+    Greyscale,
+    Truecolour,
+    Indexed,
+    GreyscaleA,
+    TruecolourA,
+}
+
+impl ColorType {
+    pub fn channels(&self) -> usize {
+        match self {
+            ColorType::Greyscale | ColorType::Indexed => 1,
+            ColorType::GreyscaleA => 2,
+            ColorType::Truecolour => 3,
+            ColorType::TruecolourA => 4,
+        }
+    }
+}
+
+/// Palette entries are [R, G, B], the PLTE chunk holds from 1 up to 256 of them.
+pub fn palette_from_chunk(plte: &Chunk) -> Result<Vec<[u8; 3]>, Error> {
+    let invalid = |reason| Error::InvalidChunk { typ: plte.typ, offset: plte.offset, reason };
+    if plte.typ != ChunkType::PLTE {
+        return Err(invalid("expected PLTE"));
+    }
+    if plte.data.is_empty() || !plte.data.len().is_multiple_of(3) || plte.data.len() > 256 * 3 {
+        return Err(invalid("length must be a multiple of 3, of 1 to 256 entries"));
+    }
+    Ok(plte.data.chunks(3).map(|x| [x[0], x[1], x[2]]).collect())
+}
+
+// tRNS, depending on the colour type:
+//  * Greyscale -> the grey level that is fully transparent, 2 bytes.
+//  * Truecolour -> the [R, G, B] colour that is fully transparent, 2 bytes each.
+//  * Indexed -> alpha of the first palette entries, the ones missing are opaque.
+//  * the ones with alpha already don´t have it.
+// Samples are compared before scaling them, so in a 4 bit greyscale image the value is between 0 and 15.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transparency {
+    Greyscale(u16),
+    Truecolour(u16, u16, u16),
+    Indexed(Vec<u8>),
+}
+
+pub fn transparency_from_chunk(trns: &Chunk, header: &ImageHeader, palette: Option<&[[u8; 3]]>) -> Result<Transparency, Error> {
+    let invalid = |reason| Error::InvalidChunk { typ: trns.typ, offset: trns.offset, reason };
+    let data = &trns.data;
+    let sample = |i: usize| u16::from_be_bytes([data[2 * i], data[2 * i + 1]]);
+    let max = ((1u32 << header.bit_depth) - 1) as u16;
+    match header.color_type {
+        ColorType::Greyscale => {
+            if data.len() != 2 {
+                return Err(invalid("length must be 2 for greyscale images"));
+            }
+            if sample(0) > max {
+                return Err(invalid("sample out of the range of the bit depth"));
+            }
+            Ok(Transparency::Greyscale(sample(0)))
+        }
+        ColorType::Truecolour => {
+            if data.len() != 6 {
+                return Err(invalid("length must be 6 for truecolour images"));
+            }
+            if header.bit_depth == 8 && (0..3).any(|i| sample(i) > max) {
+                return Err(invalid("sample out of the range of the bit depth"));
+            }
+            Ok(Transparency::Truecolour(sample(0), sample(1), sample(2)))
+        }
+        ColorType::Indexed => {
+            let palette = palette.ok_or(invalid("must come after PLTE"))?;
+            if data.len() > palette.len() {
+                return Err(invalid("more entries than the palette"));
+            }
+            Ok(Transparency::Indexed(data.clone()))
+        }
+        ColorType::GreyscaleA | ColorType::TruecolourA => Err(invalid("not allowed in images with alpha channel")),
+    }
+}
+
+/// Every pixel as [R, G, B, A], scaling the samples to 8 bits. Indexed-colour
+/// pixels take their palette entry, and the colour of a tRNS chunk becomes transparent.
+pub fn to_rgba8(header: &ImageHeader, data: &[u8], palette: Option<&[[u8; 3]]>, trns: Option<&Transparency>) -> Vec<[u8; 4]> {
+    let (width, bit_depth) = (header.width as usize, header.bit_depth);
+    let samples = width * header.color_type.channels();
+    let row_bytes = header.row_bytes();
+    if row_bytes == 0 {
+        return vec![];
+    }
+    let mut pixels = Vec::with_capacity(width * header.height as usize);
+    for row in data.chunks_exact(row_bytes).take(header.height as usize) {
+        // samples keep their original precision until the colour key is compared.
+        let row: Vec<u16> = match bit_depth {
+            16 => samples_16bit(row),
+            _ => unpack_samples(row, bit_depth, samples).into_iter().map(|x| x as u16).collect(),
+        };
+        let scale = |x: u16| match bit_depth {
+            16 => scale_16_to_8bit(x),
+            _ => scale_to_8bit(x as u8, bit_depth),
+        };
+        match header.color_type {
+            ColorType::Greyscale => pixels.extend(row.iter().map(|&x| {
+                let a = if trns == Some(&Transparency::Greyscale(x)) { 0 } else { 255 };
+                [scale(x), scale(x), scale(x), a]
+            })),
+            ColorType::Truecolour => pixels.extend(row.chunks_exact(3).map(|x| {
+                let a = if trns == Some(&Transparency::Truecolour(x[0], x[1], x[2])) { 0 } else { 255 };
+                [scale(x[0]), scale(x[1]), scale(x[2]), a]
+            })),
+            ColorType::Indexed => pixels.extend(row.iter().map(|&i| {
+                let [r, g, b] = palette.and_then(|p| p.get(i as usize)).copied().unwrap_or([0, 0, 0]);
+                let a = match trns {
+                    Some(Transparency::Indexed(alpha)) => alpha.get(i as usize).copied().unwrap_or(255),
+                    _ => 255,
+                };
+                [r, g, b, a]
+            })),
+            ColorType::GreyscaleA => pixels.extend(row.chunks_exact(2).map(|x| [scale(x[0]), scale(x[0]), scale(x[0]), scale(x[1])])),
+            ColorType::TruecolourA => pixels.extend(row.chunks_exact(4).map(|x| [scale(x[0]), scale(x[1]), scale(x[2]), scale(x[3])])),
+        }
+    }
+    pixels
+}
+
+/// Unpacks the first `count` samples of a scanline, samples of 1, 2 and 4 bits
+/// are stored from the most significant bits of each byte to the least ones.
+pub fn unpack_samples(row: &[u8], bit_depth: u8, count: usize) -> Vec<u8> {
+    if bit_depth >= 8 {
+        return row[..count].to_vec();
+    }
+    let per_byte = 8 / bit_depth as usize;
+    let mask = (1u8 << bit_depth) - 1;
+    (0..count).map(|i| {
+        let shift = 8 - bit_depth as usize * (i % per_byte + 1);
+        (row[i / per_byte] >> shift) & mask
+    }).collect()
+}
+
+/// Scales a sample of 1, 2, 4 or 8 bits to the whole 0-255 range, so 1 bit
+/// black and white becomes 0 and 255. Indices of a palette must not be scaled.
+pub fn scale_to_8bit(sample: u8, bit_depth: u8) -> u8 {
+    if bit_depth >= 8 {
+        return sample;
+    }
+    // floor(sample * 255 / MAXINSAMPLE + 0.5) is exact here, 255 is divisible by 1, 3 and 15.
+    let max = (1u16 << bit_depth) - 1;
+    (sample as u16 * 255 / max) as u8
+}
+
+/// floor(sample * 255 / 65535 + 0.5), the closest 8 bit value to a 16 bit sample.
+pub fn scale_16_to_8bit(sample: u16) -> u8 {
+    ((sample as u32 * 255 + 32767) / 65535) as u8
+}
+
+/// Samples of a 16 bit image, each one from its two big endian bytes.
+pub fn samples_16bit(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2).map(|x| u16::from_be_bytes([x[0], x[1]])).collect()
+}
+
+/// Maps every index of an indexed-colour image to its palette entry, indices
+/// out of the palette are rendered black.
+pub fn expand_palette(data: &[u8], width: u32, bit_depth: u8, palette: &[[u8; 3]]) -> Vec<[u8; 3]> {
+    let row_bytes = (width as usize * bit_depth as usize).div_ceil(8);
+    if row_bytes == 0 {
+        return vec![];
+    }
+    data.chunks(row_bytes)
+        .flat_map(|row| unpack_samples(row, bit_depth, width as usize))
+        .map(|i| palette.get(i as usize).copied().unwrap_or([0, 0, 0]))
+        .collect()
+}
+
+/// The pixels of an image with its tRNS chunk as an alpha channel, see `PNGImage::expand_trns`.
+pub(crate) fn expand_trns(header: &ImageHeader, data: &[u8], palette: Option<&[[u8; 3]]>, trns: &Transparency) -> Vec<u8> {
+    match (header.color_type, trns, header.bit_depth) {
+        (ColorType::Greyscale, &Transparency::Greyscale(key), 16) => samples_16bit(data)
+            .into_iter()
+            .flat_map(|x| [x, if x == key { 0 } else { 65535 }])
+            .flat_map(u16::to_be_bytes)
+            .collect(),
+        (ColorType::Truecolour, &Transparency::Truecolour(r, g, b), 16) => samples_16bit(data)
+            .chunks_exact(3)
+            .flat_map(|x| [x[0], x[1], x[2], if x == [r, g, b] { 0 } else { 65535 }])
+            .flat_map(u16::to_be_bytes)
+            .collect(),
+        (ColorType::Greyscale, ..) => to_rgba8(header, data, None, Some(trns)).iter().flat_map(|x| [x[0], x[3]]).collect(),
+        _ => to_rgba8(header, data, palette, Some(trns)).concat(),
+    }
+}
+
+/// The header of the pixels `expand_trns` gives.
+pub(crate) fn expand_trns_header(header: &ImageHeader) -> ImageHeader {
+    let color_type = match header.color_type {
+        ColorType::Greyscale => ColorType::GreyscaleA,
+        _ => ColorType::TruecolourA,
+    };
+    ImageHeader { color_type, bit_depth: header.bit_depth.max(8), ..*header }
+}
+
+// APNG adds three ancillary chunks, so decoders that don´t know them show the default image (the IDAT one):
+//  * acTL -> number of frames and of times to play them, before IDAT.
+//  * fcTL -> size, position, delay and how to dispose and blend a frame. When it comes before
+//            IDAT the default image is the first frame, if not it is left out of the animation.
+//  * fdAT -> sequence number and data of a frame, like the one of IDAT.
+// fcTL and fdAT share a sequence number that starts at 0 and goes up one by one.
+
+/// acTL: how many frames there are and how many times to play them, 0 for forever.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnimationControl {
+    pub num_frames: u32,
+    pub num_plays: u32,
+}
+
+/// What to do with the region of a frame before rendering the next one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisposeOp {
+    /// Leave it as it is.
+    None,
+    /// Clear it to transparent black.
+    Background,
+    /// Put back what was there before the frame.
+    Previous,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendOp {
+    /// The frame replaces the pixels of its region, alpha included.
+    Source,
+    /// The frame is composited over the pixels of its region.
+    Over,
+}
+
+/// fcTL: where a frame goes and for how long.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameControl {
+    pub sequence_number: u32,
+    pub width: u32,
+    pub height: u32,
+    pub x_offset: u32,
+    pub y_offset: u32,
+    /// The delay is delay_num / delay_den seconds, a denominator of 0 means 100.
+    pub delay_num: u16,
+    pub delay_den: u16,
+    pub dispose_op: DisposeOp,
+    pub blend_op: BlendOp,
+}
+
+impl FrameControl {
+    pub fn delay(&self) -> Duration {
+        let den = if self.delay_den == 0 { 100 } else { self.delay_den };
+        Duration::from_secs_f64(self.delay_num as f64 / den as f64)
+    }
+
+    /// Header of the image of the frame, it has the colour type, bit depth and interlace of the whole one.
+    pub fn header(&self, image: &ImageHeader) -> ImageHeader {
+        ImageHeader { width: self.width, height: self.height, ..*image }
+    }
+}
+
+pub fn animation_control_from_chunk(actl: &Chunk) -> Result<AnimationControl, Error> {
+    let invalid = |reason| Error::InvalidChunk { typ: actl.typ, offset: actl.offset, reason };
+    if actl.typ != ChunkType::acTL {
+        return Err(invalid("expected acTL"));
+    }
+    if actl.data.len() != 8 {
+        return Err(invalid("length must be 8"));
+    }
+    let num_frames = u32::from_be_bytes(actl.data[..4].try_into().expect("Valid length"));
+    let num_plays = u32::from_be_bytes(actl.data[4..].try_into().expect("Valid length"));
+    if num_frames == 0 {
+        return Err(invalid("there must be at least one frame"));
+    }
+    Ok(AnimationControl { num_frames, num_plays })
+}
+
+pub fn frame_control_from_chunk(fctl: &Chunk, header: &ImageHeader) -> Result<FrameControl, Error> {
+    let invalid = |reason| Error::InvalidChunk { typ: fctl.typ, offset: fctl.offset, reason };
+    if fctl.typ != ChunkType::fcTL {
+        return Err(invalid("expected fcTL"));
+    }
+    let data = &fctl.data;
+    if data.len() != 26 {
+        return Err(invalid("length must be 26"));
+    }
+    let u32_at = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().expect("Valid length"));
+    let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+    let dispose_op = match data[24] {
+        0 => DisposeOp::None,
+        1 => DisposeOp::Background,
+        2 => DisposeOp::Previous,
+        _ => return Err(invalid("invalid dispose operation")),
+    };
+    let blend_op = match data[25] {
+        0 => BlendOp::Source,
+        1 => BlendOp::Over,
+        _ => return Err(invalid("invalid blend operation")),
+    };
+    let control = FrameControl {
+        sequence_number: u32_at(0),
+        width: u32_at(4),
+        height: u32_at(8),
+        x_offset: u32_at(12),
+        y_offset: u32_at(16),
+        delay_num: u16_at(20),
+        delay_den: u16_at(22),
+        dispose_op,
+        blend_op,
+    };
+    if control.width == 0 || control.height == 0 {
+        return Err(invalid("the frame must not be empty"));
+    }
+    if control.x_offset as u64 + control.width as u64 > header.width as u64
+        || control.y_offset as u64 + control.height as u64 > header.height as u64
+    {
+        return Err(invalid("the frame goes out of the image"));
+    }
+    Ok(control)
+}
+
+/// A frame as it is stored, not composited yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub control: FrameControl,
+    /// Unfiltered scanlines like `PNGImage::data`, of the size of the frame.
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Animation {
+    pub control: AnimationControl,
+    /// In the order they are shown, the first one is the default image when it is part of the animation.
+    pub frames: Vec<Frame>,
+}
+
+/// A frame composited over the ones before it, see `PNGImage::frames`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderedFrame {
+    /// [R, G, B, A] bytes of the whole image.
+    pub pixels: Vec<u8>,
+    /// How long it stays on screen before the next one.
+    pub delay: Duration,
+}
+
+/// Iterator over the frames of an image, rendered on a canvas of the size of the image.
+pub struct Frames<'a> {
+    image: &'a PNGImage,
+    next: usize,
+    canvas: Vec<[u8; 4]>,
+}
+
+impl Iterator for Frames<'_> {
+    type Item = RenderedFrame;
+
+    fn next(&mut self) -> Option<RenderedFrame> {
+        let image = self.image;
+        let Some(animation) = &image.animation else {
+            // images that are not animated have just the default image.
+            self.next += 1;
+            return match self.next {
+                1 => Some(RenderedFrame { pixels: image.to_rgba8(), delay: Duration::ZERO }),
+                _ => None,
+            };
+        };
+        let frame = animation.frames.get(self.next)?;
+        let control = &frame.control;
+        let pixels = to_rgba8(&control.header(&image.header), &frame.data, image.palette.as_deref(), image.transparency.as_ref());
+        let width = image.header.width as usize;
+        let (x0, y0, w, h) = (control.x_offset as usize, control.y_offset as usize, control.width as usize, control.height as usize);
+        let region = |y: usize| (y0 + y) * width + x0..(y0 + y) * width + x0 + w;
+        // there is nothing to go back to before the first frame.
+        let dispose_op = match (control.dispose_op, self.next) {
+            (DisposeOp::Previous, 0) => DisposeOp::Background,
+            (op, _) => op,
+        };
+        let saved: Vec<[u8; 4]> = match dispose_op {
+            DisposeOp::Previous => (0..h).flat_map(|y| self.canvas[region(y)].iter().copied()).collect(),
+            _ => vec![],
+        };
+
+        for (y, row) in pixels.chunks_exact(w).enumerate() {
+            for (dst, &src) in self.canvas[region(y)].iter_mut().zip(row) {
+                *dst = match control.blend_op {
+                    BlendOp::Source => src,
+                    BlendOp::Over => blend_over(src, *dst),
+                };
+            }
+        }
+        let rendered = RenderedFrame { pixels: self.canvas.concat(), delay: control.delay() };
+
+        match dispose_op {
+            DisposeOp::None => {}
+            DisposeOp::Background => (0..h).for_each(|y| self.canvas[region(y)].fill([0; 4])),
+            DisposeOp::Previous => {
+                for (y, row) in saved.chunks_exact(w).enumerate() {
+                    self.canvas[region(y)].copy_from_slice(row);
+                }
+            }
+        }
+        self.next += 1;
+        Some(rendered)
+    }
+}
+
+/// `src` composited over `dst`, colours are not premultiplied by their alpha.
+fn blend_over(src: [u8; 4], dst: [u8; 4]) -> [u8; 4] {
+    let sa = src[3] as u32;
+    match sa {
+        255 => return src,
+        0 => return dst,
+        _ => {}
+    }
+    // alphas scaled to 0-65025 (255 * 255), the part of dst that shows through src.
+    let da = dst[3] as u32 * (255 - sa);
+    let a = sa * 255 + da;
+    let c = |i: usize| ((src[i] as u32 * sa * 255 + dst[i] as u32 * da + a / 2) / a) as u8;
+    [c(0), c(1), c(2), ((a + 127) / 255) as u8]
+}
+
+pub struct PNGImage {
+    pub header: ImageHeader,
+    pub bytes_per_pixel: u8,
+    pub palette: Option<Vec<[u8; 3]>>,
+    pub transparency: Option<Transparency>,
+    pub gamma: Option<Gamma>,
+    pub chromaticities: Option<Chromaticities>,
+    /// Some when the image says it is sRGB, then gamma and chromaticities don´t matter.
+    pub srgb: Option<RenderingIntent>,
+    /// Text chunks by keyword, when a keyword is repeated the last chunk is kept.
+    pub text: HashMap<String, Text>,
+    pub metadata: Metadata,
+    /// Ancillary chunks this decoder doesn´t know, private ones for example, as they were read.
+    pub unknown_chunks: Vec<Chunk>,
+    /// Some for APNG images, the IDAT data is still in `data`.
+    pub animation: Option<Animation>,
+    pub data: Vec<u8>,
+    pub warnings: Vec<Warning>,
+}
+
+impl PNGImage {
+    pub fn from_path(path: &str) -> Result<Self, Error> {
+        Self::from_path_with(path, &DecodeOptions::default())
+    }
+
+    pub fn from_path_with(path: &str, options: &DecodeOptions) -> Result<Self, Error> {
+        Self::from_reader_with(File::open(path)?, options)
+    }
+
+    /// Decodes a whole PNG datastream, like the ones from `include_bytes!`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Self::from_bytes_with(bytes, &DecodeOptions::default())
+    }
+
+    pub fn from_bytes_with(bytes: &[u8], options: &DecodeOptions) -> Result<Self, Error> {
+        let chunks = read_chunks(bytes)?;
+        let image = PNGImage::from_chunks_with(chunks, options)?;
+
+        Ok(image)
+    }
+
+    /// Reads until the end of `reader` and decodes what it got.
+    pub fn from_reader(reader: impl Read) -> Result<Self, Error> {
+        Self::from_reader_with(reader, &DecodeOptions::default())
+    }
+
+    pub fn from_reader_with(mut reader: impl Read, options: &DecodeOptions) -> Result<Self, Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes_with(&bytes, options)
+    }
+
+    pub fn from_chunks(chunks: Vec<Chunk>) -> Result<Self, Error> {
+        Self::from_chunks_with(chunks, &DecodeOptions::default())
+    }
+
+    pub fn from_chunks_with(chunks: Vec<Chunk>, options: &DecodeOptions) -> Result<Self, Error> {
+        use ChunkType::{acTL, cHRM, fcTL, fdAT, gAMA, iTXt, sRGB, tEXt, tRNS, zTXt, AncyllaryChunk, IDAT, IHDR, PLTE};
+        let limits = &options.limits;
+        for (index, chunk) in chunks.iter().enumerate() {
+            limits.check_chunk(index, chunk.data.len(), chunk.offset)?;
+        }
+        let mut warnings = vec![];
+        for (index, chunk) in chunks.iter().enumerate().filter(|(_, c)| !c.crc_ok()) {
+            let (typ, offset, stored, computed) = (chunk.typ, chunk.offset, chunk.crc, chunk.computed_crc());
+            match options.crc_check {
+                CrcCheck::Strict => return Err(Error::CrcMismatch { typ, offset, stored, computed }),
+                CrcCheck::Lenient => warnings.push(Warning::CrcMismatch { index, offset, typ, stored, computed }),
+            }
+        }
+        // problems with the order of the critical chunks are errors, the ones of ancillary chunks are left to `validate`.
+        if let Some(finding) = validate_chunks(&chunks).into_iter().find(Finding::is_critical) {
+            return Err(finding.into());
+        }
+        let header = ImageHeader::from_chunk(chunks.first().ok_or(Error::MissingChunk { typ: IHDR })?.clone())?;
+        limits.check_header(&header, chunks[0].offset)?;
+
+        // the filters work on whole bytes, so pixels smaller than a byte count as one.
+        let bytes_per_pixel = header.bits_per_pixel().div_ceil(8) as u8;
+
+        let mut palette = None;
+        let mut transparency = None;
+        let (mut gamma, mut chromaticities, mut srgb) = (None, None, None);
+        let mut text = HashMap::new();
+        let mut metadata = Metadata::default();
+        let mut image_data = false;
+        let mut unknown_chunks = vec![];
+        // (index, whether it came after IDAT, chunk)
+        let mut animation_chunks = vec![];
+        let mut compressed_data = vec![];
+        for (index, mut chunk) in chunks.into_iter().enumerate() {
+            match chunk.typ {
+                IDAT => {
+                    image_data = true;
+                    compressed_data.append(&mut chunk.data);
+                }
+                PLTE => palette = Some(palette_from_chunk(&chunk)?),
+                tRNS => match transparency_from_chunk(&chunk, &header, palette.as_deref()) {
+                    Ok(trns) => transparency = Some(trns),
+                    Err(e) => warnings.push(Warning::ignored(index, &chunk, e)),
+                },
+                gAMA => match gamma_from_chunk(&chunk) {
+                    Ok(g) => gamma = Some(g),
+                    Err(e) => warnings.push(Warning::ignored(index, &chunk, e)),
+                },
+                cHRM => match chromaticities_from_chunk(&chunk) {
+                    Ok(c) => chromaticities = Some(c),
+                    Err(e) => warnings.push(Warning::ignored(index, &chunk, e)),
+                },
+                sRGB => match rendering_intent_from_chunk(&chunk) {
+                    Ok(intent) => srgb = Some(intent),
+                    Err(e) => warnings.push(Warning::ignored(index, &chunk, e)),
+                },
+                tEXt | zTXt | iTXt => match text_from_chunk(&chunk, &options.inflate_options()) {
+                    Ok((keyword, value)) => {
+                        text.insert(keyword, value);
+                    }
+                    Err(e) => warnings.push(Warning::ignored(index, &chunk, e)),
+                },
+                AncyllaryChunk => unknown_chunks.push(chunk),
+                acTL | fcTL | fdAT => animation_chunks.push((index, image_data, chunk)),
+                _ => {
+                    if let Err(e) = metadata.read_chunk(&chunk, &header, palette.as_deref(), image_data) {
+                        warnings.push(Warning::ignored(index, &chunk, e));
+                    }
+                }
+            }
+        }
+        if header.color_type == ColorType::Indexed && palette.is_none() {
+            return Err(Error::MissingChunk { typ: PLTE });
+        }
+        if compressed_data.is_empty() {
+            return Err(Error::MissingChunk { typ: IDAT });
+        }
+        let decompressed_data = zlib::inflate_with(&compressed_data, &options.inflate_options())?;
+        //let decompressed_data = miniz_oxide::inflate::decompress_to_vec_zlib(&compressed_data).unwrap();
+
+        //println!("UNFILTERING..");
+        let data = Self::reconstruct(&header, &decompressed_data)?;
+        // a broken animation leaves just the default image, as if the APNG chunks were not known.
+        let animation = match Self::read_animation(&header, &animation_chunks, &data, &options.inflate_options()) {
+            Ok(animation) => animation,
+            Err((i, e)) => {
+                let (index, _, chunk) = &animation_chunks[i];
+                warnings.push(Warning::ignored(*index, chunk, e));
+                None
+            }
+        };
+
+        let mut image = Self { header, bytes_per_pixel, palette, transparency, gamma, chromaticities, srgb, text, metadata, unknown_chunks, animation, data, warnings };
+        if options.expand_trns {
+            image.expand_trns();
+        }
+        if options.convert_to_srgb {
+            image.convert_to_srgb();
+        }
+        Ok(image)
+    }
+
+    /// The acTL, fcTL and fdAT chunks as an animation, None when there is no acTL. `data` is the default
+    /// image. Errors come with the position in `chunks` of the chunk that broke the animation.
+    fn read_animation(header: &ImageHeader, chunks: &[(usize, bool, Chunk)], data: &[u8], inflate: &InflateOptions) -> Result<Option<Animation>, (usize, Error)> {
+        let mut control = None;
+        // (position of the fcTL, frame control, whether it is the default image, fdAT data)
+        let mut frames: Vec<(usize, FrameControl, bool, Vec<u8>)> = vec![];
+        let mut sequence = 0;
+        for (i, (_, image_data, chunk)) in chunks.iter().enumerate() {
+            let invalid = |reason| (i, Error::InvalidChunk { typ: chunk.typ, offset: chunk.offset, reason });
+            match chunk.typ {
+                ChunkType::acTL => {
+                    if control.is_some() {
+                        return Err(invalid("only one is allowed"));
+                    }
+                    if *image_data {
+                        return Err(invalid("must come before IDAT"));
+                    }
+                    control = Some((i, animation_control_from_chunk(chunk).map_err(|e| (i, e))?));
+                }
+                ChunkType::fcTL => {
+                    let frame = frame_control_from_chunk(chunk, header).map_err(|e| (i, e))?;
+                    if frame.sequence_number != sequence {
+                        return Err(invalid("sequence number out of order"));
+                    }
+                    sequence += 1;
+                    let default_image = !*image_data;
+                    if default_image && (frame.x_offset, frame.y_offset, frame.width, frame.height) != (0, 0, header.width, header.height) {
+                        return Err(invalid("the frame of the default image must be the whole image"));
+                    }
+                    frames.push((i, frame, default_image, vec![]));
+                }
+                _ => {
+                    if chunk.data.len() < 4 {
+                        return Err(invalid("length must be at least 4"));
+                    }
+                    if u32::from_be_bytes(chunk.data[..4].try_into().expect("Valid length")) != sequence {
+                        return Err(invalid("sequence number out of order"));
+                    }
+                    sequence += 1;
+                    match frames.last_mut() {
+                        Some((_, _, false, compressed)) => compressed.extend_from_slice(&chunk.data[4..]),
+                        _ => return Err(invalid("fdAT without a fcTL for it")),
+                    }
+                }
+            }
+        }
+        let Some((i, control)) = control else {
+            return Ok(None);
+        };
+        if frames.len() != control.num_frames as usize {
+            return Err((i, Error::InvalidChunk { typ: ChunkType::acTL, offset: chunks[i].2.offset, reason: "wrong number of frames" }));
+        }
+        let frames = frames.into_iter().map(|(i, frame, default_image, compressed)| {
+            if default_image {
+                return Ok(Frame { control: frame, data: data.to_vec() });
+            }
+            if compressed.is_empty() {
+                return Err((i, Error::InvalidChunk { typ: ChunkType::fcTL, offset: chunks[i].2.offset, reason: "frame without fdAT" }));
+            }
+            let inflated = zlib::inflate_with(&compressed, inflate).map_err(|e| (i, e))?;
+            let data = Self::reconstruct(&frame.header(header), &inflated).map_err(|e| (i, e))?;
+            Ok(Frame { control: frame, data })
+        }).collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(Animation { control, frames }))
+    }
+
+    /// Every frame of the animation composited as the APNG spec says, starting from a transparent
+    /// canvas. Images that are not animated have one frame, the image itself, with no delay.
+    pub fn frames(&self) -> Frames<'_> {
+        let size = self.header.width as usize * self.header.height as usize;
+        Frames { image: self, next: 0, canvas: vec![[0; 4]; if self.animation.is_some() { size } else { 0 }] }
+    }
+
+    /// Pixels as [R, G, B, A] bytes, whatever the colour type and bit depth.
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        to_rgba8(&self.header, &self.data, self.palette.as_deref(), self.transparency.as_ref()).concat()
+    }
+
+    /// Replaces the tRNS chunk with an alpha channel. Greyscale and truecolour images
+    /// get the alpha variant of their colour type, with samples of at least 8 bits,
+    /// and indexed-colour ones become 8 bit truecolour with alpha.
+    pub fn expand_trns(&mut self) {
+        let Some(trns) = self.transparency.take() else {
+            return;
+        };
+        let header = self.header;
+        self.data = expand_trns(&header, &self.data, self.palette.as_deref(), &trns);
+        for frame in self.animation.iter_mut().flat_map(|a| a.frames.iter_mut()) {
+            frame.data = expand_trns(&frame.control.header(&header), &frame.data, self.palette.as_deref(), &trns);
+        }
+        self.header = expand_trns_header(&header);
+        self.bytes_per_pixel = self.header.bits_per_pixel().div_ceil(8) as u8;
+    }
+
+    /// Scanlines as they are stored, without the filter type byte. Samples
+    /// smaller than a byte stay packed, the leftmost pixel in the high bits.
+    pub fn rows(&self) -> std::slice::Chunks<'_, u8> {
+        self.data.chunks(self.header.row_bytes().max(1))
+    }
+
+    /// One byte per sample, with 1, 2 and 4 bit samples unpacked but not
+    /// scaled, so they keep their original value. None for 16 bit images,
+    /// their samples don´t fit in a byte, `samples16` gives them.
+    pub fn unpacked_samples(&self) -> Option<Vec<u8>> {
+        if self.header.bit_depth > 8 {
+            return None;
+        }
+        let count = self.header.width as usize * self.header.color_type.channels();
+        Some(self.rows().flat_map(|row| unpack_samples(row, self.header.bit_depth, count)).collect())
+    }
+
+    /// Samples with their full precision, only for images of 16 bit depth.
+    pub fn samples16(&self) -> Option<Vec<u16>> {
+        match self.header.bit_depth {
+            16 => Some(samples_16bit(&self.data)),
+            _ => None,
+        }
+    }
+
+    /// Indexed-colour pixels as [R, G, B] bytes.
+    pub fn expand_palette(&self) -> Vec<u8> {
+        let palette = self.palette.as_deref().unwrap_or(&[]);
+        expand_palette(&self.data, self.header.width, self.header.bit_depth, palette).concat()
+    }
+
+    /// Indexed-colour pixels as [R, G, B, A] bytes, with the alpha of the tRNS chunk.
+    pub fn expand_palette_rgba(&self) -> Vec<u8> {
+        let alpha = match &self.transparency {
+            Some(Transparency::Indexed(alpha)) => &alpha[..],
+            _ => &[],
+        };
+        let palette = self.palette.as_deref().unwrap_or(&[]);
+        self.rows()
+            .flat_map(|row| unpack_samples(row, self.header.bit_depth, self.header.width as usize))
+            .flat_map(|i| {
+                let [r, g, b] = palette.get(i as usize).copied().unwrap_or([0, 0, 0]);
+                [r, g, b, alpha.get(i as usize).copied().unwrap_or(255)]
+            })
+            .collect()
+    }
+
+    fn PaethPredictor(a: i32, b: i32, c: i32) -> i32 {
+        let p = a + b - c;
+        let pa = i32::abs(p - a);
+        let pb = i32::abs(p - b);
+        let pc = i32::abs(p - c);
+        if pa <= pb && pa <= pc {
+            a
+        }
+        else if pb <= pc {
+            b
+        } else {
+            c
+        }
+    }
+
+    /// Turns the inflated scanlines into the final image, unfiltering every
+    /// Adam7 pass on its own when the image is interlaced.
+    fn reconstruct(header: &ImageHeader, decompressed_data: &[u8]) -> Result<Vec<u8>, Error> {
+        let bits_per_pixel = header.bits_per_pixel();
+        let bytes_per_pixel = bits_per_pixel.div_ceil(8);
+        // checked before allocating anything, the header could be asking for gigabytes.
+        if decompressed_data.len() < header.data_size()? {
+            return Err(Error::TruncatedImageData { offset: decompressed_data.len() });
+        }
+        if header.interlace == Interlace::None {
+            return Self::unfilter(decompressed_data, header.height as usize, header.row_bytes(), bytes_per_pixel, 0);
+        }
+
+        let (width, height) = (header.width as usize, header.height as usize);
+        let row_bytes = header.row_bytes();
+        let mut data = vec![0u8; row_bytes * height];
+        let mut start = 0;
+        for (pass, &(x0, y0, dx, dy)) in ADAM7.iter().enumerate() {
+            let (w, h) = adam7_pass_size(width, height, pass);
+            if w == 0 || h == 0 {
+                continue;
+            }
+            let cols = (w * bits_per_pixel).div_ceil(8);
+            let end = start + h * (cols + 1);
+            let reduced = Self::unfilter(&decompressed_data[start..], h, cols, bytes_per_pixel, start)?;
+            start = end;
+
+            for (i, row) in reduced.chunks(cols).enumerate() {
+                let y = y0 + i * dy;
+                let dst = &mut data[y * row_bytes..(y + 1) * row_bytes];
+                for j in 0..w {
+                    let x = x0 + j * dx;
+                    if bits_per_pixel >= 8 {
+                        dst[x * bytes_per_pixel..(x + 1) * bytes_per_pixel]
+                            .copy_from_slice(&row[j * bytes_per_pixel..(j + 1) * bytes_per_pixel]);
+                    } else {
+                        // sub-byte pixels: move the bits from their position in the pass to the final one.
+                        let mask = (1u8 << bits_per_pixel) - 1;
+                        let src_shift = 8 - bits_per_pixel * (j % (8 / bits_per_pixel) + 1);
+                        let dst_shift = 8 - bits_per_pixel * (x % (8 / bits_per_pixel) + 1);
+                        let value = (row[j * bits_per_pixel / 8] >> src_shift) & mask;
+                        dst[x * bits_per_pixel / 8] |= value << dst_shift;
+                    }
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// `offset` is where `decompressed_data` starts in the whole inflated stream, for the errors.
+    fn unfilter(decompressed_data: &[u8], rows: usize, cols: usize, bytes_per_pixel: usize, offset: usize) -> Result<Vec<u8>, Error> {
+        if decompressed_data.len() < rows * (cols + 1) {
+            return Err(Error::TruncatedImageData { offset: offset + decompressed_data.len() });
+        }
+        let mut data = vec![0u8; rows * cols];
+        for i in 0..rows {
+            let line = &decompressed_data[i * (cols + 1)..(i + 1) * (cols + 1)];
+            let (done, current) = data.split_at_mut(i * cols);
+            let previous = match i > 0 {
+                true => &done[(i - 1) * cols..],
+                false => &[],
+            };
+            current[..cols].copy_from_slice(&line[1..]);
+            Self::unfilter_row(line[0], &mut current[..cols], previous, bytes_per_pixel, offset + i * (cols + 1))?;
+        }
+        Ok(data)
+    }
+
+    /// Undoes the filter of one scanline in place. `previous` is the already unfiltered
+    /// scanline above, empty for the first one of the image (or of the Adam7 pass).
+    pub(crate) fn unfilter_row(typ: u8, current: &mut [u8], previous: &[u8], bytes_per_pixel: usize, offset: usize) -> Result<(), Error> {
+        //┌───┬──────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────┐
+        //│ x │	the byte being filtered;					                                                                                                             │
+        //│ a │	the byte corresponding to x in the pixel immediately before the pixel containing x (or the byte immediately before x, when the bit depth is less than 8);│
+        //│ b │	the byte corresponding to x in the previous scanline;                                                                                                    │
+        //│ c │	the byte corresponding to b in the pixel immediately before the pixel containing b (or the byte immediately before b, when the bit depth is less than 8).│
+        //└───┴──────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────────┘
+        if typ > 4 {
+            return Err(Error::InvalidFilterType { filter: typ, offset });
+        }
+        for j in 0..current.len() {
+            let x = current[j] as u16;
+            let (left, up) = (j >= bytes_per_pixel, !previous.is_empty());
+            let a = match left {
+                true => current[j - bytes_per_pixel] as u16,
+                false => 0,
+            };
+
+            let b = match up {
+                true =>  previous[j] as u16,
+                false => 0,
+            };
+            let c = match left && up {
+                true => previous[j - bytes_per_pixel] as u16,
+                false => 0,
+            };
+            current[j] = match typ {
+                0 => x as u8,
+                1 => ((x + a) & 255) as u8,
+                2 => ((x + b) & 255) as u8,
+                3 => ((x + ((a as usize + b as usize) / 2) as u16 ) & 255) as u8,
+                4 => ((x + (Self::PaethPredictor(a as i32, b as i32 , c as i32)) as u16) & 255) as u8, //x.wrapping_add(),
+                _ => unreachable!(),
+            };
+        }
+        Ok(())
+    }
+
+    /// The other way around of `unfilter_row`, adds the filtered scanline to `out`. `previous`
+    /// is the scanline above as it is (not filtered), empty for the first one. `typ` is 0 to 4.
+    pub(crate) fn filter_row(typ: u8, current: &[u8], previous: &[u8], bytes_per_pixel: usize, out: &mut Vec<u8>) {
+        // same a, b and c as when unfiltering, the bytes before filtering.
+        for j in 0..current.len() {
+            let x = current[j];
+            let (left, up) = (j >= bytes_per_pixel, !previous.is_empty());
+            let a = if left { current[j - bytes_per_pixel] } else { 0 };
+            let b = if up { previous[j] } else { 0 };
+            let c = if left && up { previous[j - bytes_per_pixel] } else { 0 };
+            out.push(match typ {
+                0 => x,
+                1 => x.wrapping_sub(a),
+                2 => x.wrapping_sub(b),
+                3 => x.wrapping_sub(((a as u16 + b as u16) / 2) as u8),
+                4 => x.wrapping_sub(Self::PaethPredictor(a as i32, b as i32, c as i32) as u8),
+                _ => unreachable!(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(color_type: ColorType, bit_depth: u8, width: u32, data: Vec<u8>) -> PNGImage {
+        let header = ImageHeader { width, height: 1, bit_depth, color_type, interlace: Interlace::None };
+        PNGImage {
+            header,
+            bytes_per_pixel: header.bits_per_pixel().div_ceil(8) as u8,
+            palette: None,
+            transparency: None,
+            gamma: None,
+            chromaticities: None,
+            srgb: None,
+            text: HashMap::new(),
+            metadata: Metadata::default(),
+            unknown_chunks: vec![],
+            animation: None,
+            data,
+            warnings: vec![],
+        }
+    }
+
+    #[test]
+    fn unpacked_samples() {
+        // the leftmost pixel in the high bits, and the row padded to a whole byte.
+        let packed = image(ColorType::Greyscale, 2, 5, vec![0b00_01_10_11, 0b11_000000]);
+        assert_eq!(packed.unpacked_samples(), Some(vec![0, 1, 2, 3, 3]));
+        let bytes = image(ColorType::GreyscaleA, 8, 2, vec![7, 200, 8, 100]);
+        assert_eq!(bytes.unpacked_samples(), Some(vec![7, 200, 8, 100]));
+        // 16 bit samples don´t fit in a byte.
+        assert_eq!(image(ColorType::Greyscale, 16, 2, vec![1, 2, 3, 4]).unpacked_samples(), None);
+    }
+
+    #[test]
+    fn limits() {
+        let limits = Limits { max_width: 100, max_height: 50, max_pixels: 4000, max_chunk_length: 1000, max_chunks: 10, ..Limits::default() };
+        let header = |width, height| ImageHeader { width, height, bit_depth: 8, color_type: ColorType::Truecolour, interlace: Interlace::None };
+        let limit = |result: Result<(), Error>| match result {
+            Err(Error::LimitExceeded { limit, offset: 8 }) => Some(limit),
+            Ok(()) => None,
+            Err(e) => panic!("{e}"),
+        };
+        assert_eq!(limit(limits.check_header(&header(100, 40), 8)), None);
+        assert_eq!(limit(limits.check_header(&header(101, 1), 8)), Some("image too wide"));
+        assert_eq!(limit(limits.check_header(&header(1, 51), 8)), Some("image too tall"));
+        assert_eq!(limit(limits.check_header(&header(100, 41), 8)), Some("too many pixels"));
+
+        assert_eq!(limit(limits.check_chunk(9, 1000, 8)), None);
+        assert_eq!(limit(limits.check_chunk(9, 1001, 8)), Some("chunk too long"));
+        assert_eq!(limit(limits.check_chunk(10, 0, 8)), Some("too many chunks"));
+    }
+
+    #[test]
+    fn data_size() {
+        let header = |width, height, interlace| ImageHeader { width, height, bit_depth: 16, color_type: ColorType::TruecolourA, interlace };
+        assert_eq!(header(3, 2, Interlace::None).data_size().unwrap(), 2 * (3 * 8 + 1));
+        // passes 1 and 6 have one pixel, 7 the two of the second row and the others none.
+        assert_eq!(header(2, 2, Interlace::Adam7).data_size().unwrap(), 2 * (8 + 1) + (2 * 8 + 1));
+        for interlace in [Interlace::None, Interlace::Adam7] {
+            let size = header(u32::MAX, u32::MAX, interlace).data_size();
+            assert!(matches!(size, Err(Error::LimitExceeded { offset: 8, .. })), "{size:?}");
+        }
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let original = image(ColorType::Truecolour, 8, 3, vec![10, 20, 30, 40, 50, 60, 70, 80, 90]);
+        let mut bytes = original.to_bytes().unwrap();
+        // IHDR takes 25 bytes after the signature, the IDAT chunk comes next.
+        let length = u32::from_be_bytes(bytes[33..37].try_into().unwrap()) as usize;
+        let crc = 33 + 8 + length;
+        let stored = u32::from_be_bytes(bytes[crc..crc + 4].try_into().unwrap());
+        bytes[crc] ^= 1;
+        let damaged = stored ^ 1 << 24;
+
+        let strict = PNGImage::from_bytes(&bytes).err();
+        assert!(matches!(strict, Some(Error::CrcMismatch { typ: ChunkType::IDAT, offset: 33, stored: s, computed: c })
+            if s == damaged && c == stored), "{strict:?}");
+
+        let options = DecodeOptions { crc_check: CrcCheck::Lenient, ..Default::default() };
+        let lenient = PNGImage::from_bytes_with(&bytes, &options).unwrap();
+        assert_eq!(lenient.warnings, [Warning::CrcMismatch { index: 1, offset: 33, typ: ChunkType::IDAT, stored: damaged, computed: stored }]);
+        assert_eq!(lenient.data, original.data);
+    }
+
+    #[test]
+    fn frames() {
+        const RED: [u8; 4] = [255, 0, 0, 255];
+        const BLUE: [u8; 4] = [0, 0, 255, 128];
+        let control = |sequence_number, x_offset, y_offset, size, dispose_op, blend_op| {
+            FrameControl { sequence_number, width: size, height: size, x_offset, y_offset, delay_num: 1, delay_den: 10, dispose_op, blend_op }
+        };
+        let animated = |frames: Vec<Frame>| {
+            let mut image = image(ColorType::TruecolourA, 8, 2, RED.repeat(4));
+            image.header.height = 2;
+            image.animation = Some(Animation { control: AnimationControl { num_frames: frames.len() as u32, num_plays: 0 }, frames });
+            image.frames().map(|f| f.pixels).collect::<Vec<_>>()
+        };
+        // the bottom right pixel of the 2x2 canvas, the other three stay red.
+        let canvas = |corner: [u8; 4]| [RED, RED, RED, corner].concat();
+
+        // a red frame, a half transparent blue pixel over its corner and then a transparent
+        // pixel blended over the top left one, which shows how the blue one was disposed of.
+        for dispose_op in [DisposeOp::None, DisposeOp::Background, DisposeOp::Previous] {
+            for blend_op in [BlendOp::Source, BlendOp::Over] {
+                let frames = animated(vec![
+                    Frame { control: control(0, 0, 0, 2, DisposeOp::None, BlendOp::Source), data: RED.repeat(4) },
+                    Frame { control: control(1, 1, 1, 1, dispose_op, blend_op), data: BLUE.to_vec() },
+                    Frame { control: control(3, 0, 0, 1, DisposeOp::None, BlendOp::Over), data: vec![0; 4] },
+                ]);
+                let blue = match blend_op {
+                    BlendOp::Source => BLUE,
+                    BlendOp::Over => [127, 0, 128, 255],
+                };
+                let disposed = match dispose_op {
+                    DisposeOp::None => blue,
+                    DisposeOp::Background => [0; 4],
+                    DisposeOp::Previous => RED,
+                };
+                assert_eq!(frames, [canvas(RED), canvas(blue), canvas(disposed)], "{dispose_op:?} {blend_op:?}");
+            }
+        }
+
+        // there is nothing to go back to after the first frame, so it is cleared.
+        let frames = animated(vec![
+            Frame { control: control(0, 0, 0, 2, DisposeOp::Previous, BlendOp::Source), data: RED.repeat(4) },
+            Frame { control: control(1, 1, 1, 1, DisposeOp::None, BlendOp::Over), data: BLUE.to_vec() },
+        ]);
+        assert_eq!(frames, [canvas(RED), [[0; 4], [0; 4], [0; 4], BLUE].concat()]);
+    }
+}
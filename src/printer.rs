@@ -1,214 +1,317 @@
-use std::{io::{stdout, Write}, ops::{Div, Mul, Add}};
-
-use crossterm::{style::{Color, PrintStyledContent, Stylize, Print}, queue};
-
-use crate::png::*;
-// greyscale -> [grey, grey, grey]
-//true color -> [r, b ,g]
-// ture color A -> [r, g, b] ignore alpha
-// greyscale A => [grey, grey, gey] -> ignore alpha
-/* 
-The most accurate scaling is achieved by the linear equation
-
-output = floor((input * MAXOUTSAMPLE / MAXINSAMPLE) + 0.5)
-
-where
-
-MAXINSAMPLE = (2 ^ sampledepth) - 1
-MAXOUTSAMPLE = (2 ^ desired_sampledepth) - 1
-
-Cause we are going to print them to terminal we dont care that much about precission
-so the approximate formula would be input / 2 ^ (real_bit_depth - 8) which would result
-in shifting that many bites to right, or just taking first chunk from raw bytes cause we are
-in big endian.
-*/
-pub struct ImgData {
-    h: u32,
-    w: u32,
-    pixels: Vec<Pixel>,
-}
-
-impl ImgData {
-    pub fn new(w: u32, h: u32, pixels: &[u8], typ: ColorType, bit_depth: u8) -> ImgData {
-
-        let pixels = pixels.chunks((bit_depth as usize + 7) / 8usize).map(|x| x[0]).collect::<Vec<u8>>();
-        
-        let pixels: Vec<Pixel> = match typ {
-            ColorType::Greyscale => pixels.chunks(1).map(|x| Pixel::from_u8(x[0], x[0], x[0])).collect(),
-            ColorType::GreyscaleA => pixels.chunks(2).map(|x| Pixel::from_u8(x[0], x[0], x[0])).collect(),
-            ColorType::Truecolour => pixels.chunks(3).map(|x| Pixel::from_u8(x[0], x[1], x[2])).collect(),
-            ColorType::TruecolourA => pixels.chunks(4).map(|x| Pixel::from_u8(x[0], x[1], x[2])).collect(),
-        };
-
-        return ImgData { h, w, pixels };
-    }
-
-}
-
-pub fn print(img: ImgData) {
-    println!("PRINTING...");
-    let mut stdout = stdout();
-
-    let n = img.h as usize;
-    let w = img.w as usize;
-
-    for dx in 0..(n/2) {
-        for col in 0..w {
-            let row = 2 * dx;
-            let upper_pixel = img.pixels[row * w + col];
-            let lower_pixel = img.pixels[(row + 1) * w + col];
-            let color_upper_pixel = Color::Rgb { r: upper_pixel.r as u8, g: upper_pixel.g as u8, b: upper_pixel.b as u8 };
-            let color_lower_pixel = Color::Rgb { r: lower_pixel.r as u8, g: lower_pixel.g as u8, b: lower_pixel.b as u8 };
-            queue!(stdout, PrintStyledContent("\u{2580}".with(color_upper_pixel).on(color_lower_pixel))).expect("msg");
-        }
-        queue!(stdout, Print("\n")).expect("msg");
-    }
-
-    if n % 2 != 0 {
-        for col in 0..w {
-            let px = img.pixels[(n - 1) * w + col];
-            let color_px = Color::Rgb { r: px.r as u8, g: px.g as u8, b: px.b as u8 };
-            queue!(stdout, PrintStyledContent("\u{2580}".with(color_px))).expect("msg");
-        }
-        queue!(stdout, Print("\n")).expect("msg");
-    }
-
-    stdout.flush().expect("msg");
-}
-
-pub fn resize(mut img: ImgData, t_width: f32) -> ImgData {
-    println!("RESIZING...");
-    let (w, h) = (img.w as f32, img.h as f32);
-    let w2 = t_width as usize;
-    let m_x = (h / w) * t_width;
-    let h2 = m_x as usize;
-    //println!("current width: {}", w2);
-    //println!("current height: {}", h2);
-    let (mult_w, mult_h) = (w / w2 as f32, h / h2 as f32);
-    //println!("1 current pixel = {} original pixels in width", mult_w);
-    //println!("1 current pixel = {} original pixels in height", mult_h);
-
-    //println!("PROCESSING PIXELS");
-    let mut new_pixels = Vec::with_capacity(h2 * w2);
-    for i in 0..h2 {
-        for j in 0..w2 {
-            let px = process_rows(i as f32, j as f32, mult_h, &img.pixels, mult_w, w as usize);
-            new_pixels.push(px);
-        }
-    }
-
-    img.w = w2 as u32;
-    img.h = h2 as u32;
-    img.pixels = new_pixels;
-
-    return img;
-}
-
-#[derive(Default, Debug, Clone, Copy)]
-pub struct Pixel {
-    r: usize,
-    g: usize,
-    b: usize,
-}
-impl Pixel {
-    fn from_u8(r: u8, g: u8, b: u8) -> Pixel {
-        return Pixel {
-            r: r as usize,
-            g: g as usize,
-            b: b as usize,
-        };
-    }
-}
-
-impl Add for Pixel {
-    type Output = Pixel;
-    fn add(self, rhs: Pixel) -> Self::Output {
-        return Pixel { r: self.r + rhs.r, g: self.g + rhs.g, b: self.b + rhs.b, };
-    }
-}
-
-impl Mul<usize> for Pixel {
-    type Output = Pixel;
-    fn mul(self, rhs: usize) -> Self::Output {
-        return Pixel { r: self.r * rhs, g: self.g * rhs, b: self.b * rhs, };
-    }
-}
-
-impl Div<f32> for Pixel {
-    type Output = Pixel;
-    fn div(self, rhs: f32) -> Self::Output {
-        return Pixel { r: (self.r as f32 / rhs) as usize, g: (self.g as f32 / rhs) as usize, b: (self.b as f32 / rhs) as usize, };
-    }
-}
-
-fn process_pixel(n_col: f32, mult: f32, data: &[Pixel]) -> Pixel {
-    //println!("+=======PROCESSING PIXEL===========+");
-    let start = two_digits(mult * n_col);
-    let end = two_digits(mult * (n_col + 1.0));
-    let t_rate = start.ceil() - start + end - end.floor();
-    let f_rate = (((start.ceil() - start) / t_rate) * 10.0).floor() as usize;
-    let l_rate = (((end - end.floor()) / t_rate) * 10.0).floor() as usize;
-
-    //println!("first: {} ~= {}", start.ceil() - start, f_rate);
-    //println!("last: {} ~= {}", end - end.floor(), l_rate);
-    let count = end as usize - start.ceil() as usize;
-    let mut px_total = Pixel::default();
-
-    //println!("principio {:?}", data[start as usize]);
-    for px in start.ceil() as usize..end as usize {
-        px_total = px_total + data[px] * 10;
-    }
-    //println!("fin {:?}", data[end as usize]);
-
-    if (end as usize) < data.len() {
-        px_total = px_total + data[start as usize] * (f_rate) + data[end as usize] * (l_rate);
-    }else {
-        px_total = px_total + data[start as usize] * (f_rate) ;//+ data[end as usize] * (l_rate);
-    }
-
-    return px_total / (10*count + f_rate + l_rate) as f32;
-}
-
-fn process_rows(n_row: f32, n_col: f32, mult: f32, data: &[Pixel], mult2: f32, pixels_per_row: usize) -> Pixel {
-    let start = two_digits(mult * n_row);
-    let end = two_digits(mult * (n_row + 1.0));
-    let t_rate = start.ceil() - start + end - end.floor();
-    let f_rate = (((start.ceil() - start) / t_rate) * 10.0).floor() as usize;
-    let l_rate = (((end - end.floor()) / t_rate) * 10.0).floor() as usize;
-
-    let count = end as usize - start.ceil() as usize;
-    let mut px_total = Pixel::default();
-
-    for _row in start.ceil() as usize..end as usize {
-        px_total = px_total + process_pixel(n_col, mult2, &data[pixels_per_row*_row as usize..]) * 10;
-    }
-
-    if data[pixels_per_row*end as usize..].len() > 0{
-        px_total = px_total + process_pixel(n_col, mult2, &data[pixels_per_row*start as usize..]) * (f_rate) 
-                   + process_pixel(n_col, mult2, &data[pixels_per_row*end as usize..]) * (l_rate);
-    }else {
-        px_total = px_total + process_pixel(n_col, mult2, &data[pixels_per_row*start as usize..]) * (f_rate);
-    }
-    
-    return px_total / (10*count + f_rate + l_rate) as f32;
-}
-
-fn two_digits(n: f32) -> f32 {
-    return (n * 10.0).trunc()/ 10.0;
-}
-//#[cfg(test)]
-//mod tests {
-//    use crate::PNGImage;
-//
-//    use super::resize;
-//
-//    #[test]
-//    fn test1() {
-//        let img = PNGImage {
-//            bytes_per_pixel: 3,
-//            data: vec![],
-//            header: crate::ImageHeader { width: 600, height: 700, bit_depth: 8, color_type: crate::ColorType::Truecolour }
-//        };
-//        resize(&img, 0.0, 100.0);
-//    }
-//}
+use std::{io::{stdout, Stdout, Write}, ops::{Div, Mul, Add}, time::{Duration, Instant}};
+
+use crossterm::{cursor, event::{self, Event, KeyEventKind}, execute, style::{Color, PrintStyledContent, Stylize, Print}, queue, terminal};
+
+use crate::{png::*, Error};
+// greyscale -> [grey, grey, grey]
+//true color -> [r, b ,g]
+// indexed -> palette[index] = [r, g, b]
+// ture color A -> [r, g, b, a]
+// greyscale A => [grey, grey, gey, a]
+// pixels with less than half alpha, or with the colour of the tRNS chunk, are left transparent.
+/* 
+The most accurate scaling is achieved by the linear equation
+
+output = floor((input * MAXOUTSAMPLE / MAXINSAMPLE) + 0.5)
+
+where
+
+MAXINSAMPLE = (2 ^ sampledepth) - 1
+MAXOUTSAMPLE = (2 ^ desired_sampledepth) - 1
+
+Taking just the high byte of 16 bit samples (input / 2 ^ 8) is off by one for about
+half the values, so they go through this formula too, see scale_16_to_8bit.
+*/
+pub struct ImgData {
+    h: u32,
+    w: u32,
+    pixels: Vec<Pixel>,
+}
+
+impl ImgData {
+    pub fn new(w: u32, h: u32, pixels: &[u8], typ: ColorType, bit_depth: u8, palette: Option<&[[u8; 3]]>, trns: Option<&Transparency>) -> Result<ImgData, Error> {
+        let header = ImageHeader { width: w, height: h, bit_depth, color_type: typ, interlace: Interlace::None };
+        if pixels.len() < header.row_bytes() * h as usize {
+            return Err(Error::TruncatedImageData { offset: pixels.len() });
+        }
+        if typ == ColorType::Indexed && palette.is_none() {
+            return Err(Error::MissingChunk { typ: ChunkType::PLTE });
+        }
+
+        let pixels = to_rgba8(&header, pixels, palette, trns)
+            .iter()
+            .map(|&[r, g, b, a]| Pixel::from_u8(r, g, b, a))
+            .collect();
+
+        Ok(ImgData { h, w, pixels })
+    }
+
+    /// Pixels that are already [R, G, B, A] bytes, like the frames of an animation.
+    pub fn from_rgba8(w: u32, h: u32, pixels: &[u8]) -> Result<ImgData, Error> {
+        if pixels.len() < w as usize * h as usize * 4 {
+            return Err(Error::TruncatedImageData { offset: pixels.len() });
+        }
+        let pixels = pixels
+            .chunks_exact(4)
+            .take(w as usize * h as usize)
+            .map(|p| Pixel::from_u8(p[0], p[1], p[2], p[3]))
+            .collect();
+        Ok(ImgData { h, w, pixels })
+    }
+}
+
+pub fn print(img: ImgData) -> Result<(), Error> {
+    let mut stdout = stdout();
+    draw(&mut stdout, &img)?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Shows the frames one after the other in the same place of the terminal, resized to `t_width`
+/// columns like `resize` does. They are played `num_plays` times (0 is forever), or until a key is
+/// pressed. Afterwards the terminal is back as it was, with the cursor under the image.
+pub fn play(frames: Vec<(ImgData, Duration)>, t_width: f32, num_plays: u32) -> Result<(), Error> {
+    let frames: Vec<_> = frames.into_iter().map(|(img, delay)| (scale(img, t_width), delay)).collect();
+    let Some((first, _)) = frames.first() else {
+        return Ok(());
+    };
+    let rows = first.h.div_ceil(2) as u16;
+    if rows == 0 {
+        return Ok(());
+    }
+    let mut stdout = stdout();
+    // room for the image first, so drawing it doesn´t scroll the terminal and move the place it goes.
+    queue!(stdout, Print("\n".repeat(rows as usize)), cursor::MoveUp(rows), cursor::SavePosition)?;
+    stdout.flush()?;
+
+    let _raw = RawMode::enable()?;
+    let mut plays = 0;
+    'playing: loop {
+        for (img, delay) in &frames {
+            queue!(stdout, cursor::RestorePosition)?;
+            draw(&mut stdout, img)?;
+            stdout.flush()?;
+            if key_pressed(*delay)? {
+                break 'playing;
+            }
+        }
+        plays += 1;
+        if plays == num_plays {
+            break;
+        }
+    }
+    queue!(stdout, cursor::RestorePosition, cursor::MoveDown(rows))?;
+    stdout.flush()?;
+    Ok(())
+}
+
+// Raw mode lets a key press stop the animation without waiting for enter. Dropping this puts
+// the terminal back as it was, also when playing stops because of an error.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Result<RawMode, Error> {
+        terminal::enable_raw_mode()?;
+        execute!(stdout(), cursor::Hide)?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = execute!(stdout(), cursor::Show);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Waits for `delay`, returning early if a key is pressed. Other events, like resizing the terminal, are skipped.
+fn key_pressed(delay: Duration) -> Result<bool, Error> {
+    let end = Instant::now() + delay;
+    loop {
+        let left = end.saturating_duration_since(Instant::now());
+        if !event::poll(left)? {
+            return Ok(false);
+        }
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Queues the image, two pixels per cell. Rows end with "\r\n" so it also works in raw mode.
+fn draw(stdout: &mut Stdout, img: &ImgData) -> Result<(), Error> {
+    let n = img.h as usize;
+    let w = img.w as usize;
+
+    for dx in 0..(n/2) {
+        for col in 0..w {
+            let row = 2 * dx;
+            let upper_pixel = img.pixels[row * w + col];
+            let lower_pixel = img.pixels[(row + 1) * w + col];
+            // transparent halves keep the background of the terminal.
+            let cell = match (upper_pixel.color(), lower_pixel.color()) {
+                (Some(upper), Some(lower)) => "\u{2580}".with(upper).on(lower),
+                (Some(upper), None) => "\u{2580}".with(upper),
+                (None, Some(lower)) => "\u{2584}".with(lower),
+                (None, None) => " ".stylize(),
+            };
+            queue!(stdout, PrintStyledContent(cell))?;
+        }
+        queue!(stdout, Print("\r\n"))?;
+    }
+
+    if !n.is_multiple_of(2) {
+        for col in 0..w {
+            let px = img.pixels[(n - 1) * w + col];
+            let cell = match px.color() {
+                Some(color_px) => "\u{2580}".with(color_px),
+                None => " ".stylize(),
+            };
+            queue!(stdout, PrintStyledContent(cell))?;
+        }
+        queue!(stdout, Print("\r\n"))?;
+    }
+    Ok(())
+}
+
+pub fn resize(img: ImgData, t_width: f32) -> ImgData {
+    scale(img, t_width)
+}
+
+fn scale(mut img: ImgData, t_width: f32) -> ImgData {
+    let (w, h) = (img.w as f32, img.h as f32);
+    let w2 = t_width as usize;
+    let m_x = (h / w) * t_width;
+    let h2 = m_x as usize;
+    let (mult_w, mult_h) = (w / w2 as f32, h / h2 as f32);
+
+    let mut new_pixels = Vec::with_capacity(h2 * w2);
+    for i in 0..h2 {
+        for j in 0..w2 {
+            let px = process_rows(i as f32, j as f32, mult_h, &img.pixels, mult_w, w as usize);
+            new_pixels.push(px);
+        }
+    }
+
+    img.w = w2 as u32;
+    img.h = h2 as u32;
+    img.pixels = new_pixels;
+
+    img
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Pixel {
+    r: usize,
+    g: usize,
+    b: usize,
+    a: usize,
+}
+impl Pixel {
+    fn from_u8(r: u8, g: u8, b: u8, a: u8) -> Pixel {
+        Pixel {
+            r: r as usize,
+            g: g as usize,
+            b: b as usize,
+            a: a as usize,
+        }
+    }
+
+    /// None when the pixel is more transparent than opaque.
+    fn color(&self) -> Option<Color> {
+        match self.a < 128 {
+            true => None,
+            false => Some(Color::Rgb { r: self.r as u8, g: self.g as u8, b: self.b as u8 }),
+        }
+    }
+}
+
+impl Add for Pixel {
+    type Output = Pixel;
+    fn add(self, rhs: Pixel) -> Self::Output {
+        Pixel { r: self.r + rhs.r, g: self.g + rhs.g, b: self.b + rhs.b, a: self.a + rhs.a, }
+    }
+}
+
+impl Mul<usize> for Pixel {
+    type Output = Pixel;
+    fn mul(self, rhs: usize) -> Self::Output {
+        Pixel { r: self.r * rhs, g: self.g * rhs, b: self.b * rhs, a: self.a * rhs, }
+    }
+}
+
+impl Div<f32> for Pixel {
+    type Output = Pixel;
+    fn div(self, rhs: f32) -> Self::Output {
+        Pixel { r: (self.r as f32 / rhs) as usize, g: (self.g as f32 / rhs) as usize, b: (self.b as f32 / rhs) as usize, a: (self.a as f32 / rhs) as usize, }
+    }
+}
+
+fn process_pixel(n_col: f32, mult: f32, data: &[Pixel]) -> Pixel {
+    let start = two_digits(mult * n_col);
+    let end = two_digits(mult * (n_col + 1.0));
+    let t_rate = start.ceil() - start + end - end.floor();
+    let f_rate = (((start.ceil() - start) / t_rate) * 10.0).floor() as usize;
+    let l_rate = (((end - end.floor()) / t_rate) * 10.0).floor() as usize;
+
+    let count = end as usize - start.ceil() as usize;
+    let mut px_total = Pixel::default();
+
+    for px in &data[start.ceil() as usize..end as usize] {
+        px_total = px_total + *px * 10;
+    }
+
+    if (end as usize) < data.len() {
+        px_total = px_total + data[start as usize] * (f_rate) + data[end as usize] * (l_rate);
+    }else {
+        px_total = px_total + data[start as usize] * (f_rate) ;//+ data[end as usize] * (l_rate);
+    }
+
+    px_total / (10*count + f_rate + l_rate) as f32
+}
+
+fn process_rows(n_row: f32, n_col: f32, mult: f32, data: &[Pixel], mult2: f32, pixels_per_row: usize) -> Pixel {
+    let start = two_digits(mult * n_row);
+    let end = two_digits(mult * (n_row + 1.0));
+    let t_rate = start.ceil() - start + end - end.floor();
+    let f_rate = (((start.ceil() - start) / t_rate) * 10.0).floor() as usize;
+    let l_rate = (((end - end.floor()) / t_rate) * 10.0).floor() as usize;
+
+    let count = end as usize - start.ceil() as usize;
+    let mut px_total = Pixel::default();
+
+    for _row in start.ceil() as usize..end as usize {
+        px_total = px_total + process_pixel(n_col, mult2, &data[pixels_per_row*_row..]) * 10;
+    }
+
+    if !data[pixels_per_row*end as usize..].is_empty() {
+        px_total = px_total + process_pixel(n_col, mult2, &data[pixels_per_row*start as usize..]) * (f_rate) 
+                   + process_pixel(n_col, mult2, &data[pixels_per_row*end as usize..]) * (l_rate);
+    }else {
+        px_total = px_total + process_pixel(n_col, mult2, &data[pixels_per_row*start as usize..]) * (f_rate);
+    }
+    
+    px_total / (10*count + f_rate + l_rate) as f32
+}
+
+fn two_digits(n: f32) -> f32 {
+    (n * 10.0).trunc()/ 10.0
+}
+//#[cfg(test)]
+//mod tests {
+//    use crate::PNGImage;
+//
+//    use super::resize;
+//
+//    #[test]
+//    fn test1() {
+//        let img = PNGImage {
+//            bytes_per_pixel: 3,
+//            data: vec![],
+//            header: crate::ImageHeader { width: 600, height: 700, bit_depth: 8, color_type: crate::ColorType::Truecolour }
+//        };
+//        resize(&img, 0.0, 100.0);
+//    }
+//}
@@ -0,0 +1,604 @@
+use std::collections::HashMap;
+
+use crate::{color::*, metadata::*, png::*, text::*, zlib::{Inflater, Status}, Error};
+
+/// A scanline that is ready before the rest of the image, already unfiltered.
+#[derive(Debug)]
+pub struct Scanline<'a> {
+    /// 0 for images that are not interlaced, 1 to 7 for the Adam7 passes.
+    pub pass: usize,
+    /// Row of the whole image where this scanline goes.
+    pub y: usize,
+    /// The pixels of the scanline go to the columns x_start, x_start + x_step, ...
+    pub x_start: usize,
+    pub x_step: usize,
+    /// Pixels packed the same way as in `PNGImage::data` of an image decoded with the same options.
+    pub data: &'a [u8],
+}
+
+enum State {
+    Signature,
+    ChunkHeader,
+    // `start` is the offset of the chunk in the datastream.
+    ImageData { start: usize, remaining: usize, crc: u32 },
+    ImageDataCrc { start: usize, crc: u32 },
+    Chunk { start: usize, typ: ChunkType, name: [u8; 4], length: usize },
+    End,
+}
+
+// most inflated bytes taken from the inflater at once.
+const INFLATE_STEP: usize = 32 * 1024;
+
+// (pass number, width, height, starting column, starting row, column increment, row increment)
+type Pass = (usize, usize, usize, usize, usize, usize, usize);
+
+/// Push decoder: bytes are fed as they arrive, and every scanline is given to a
+/// callback as soon as its part of the IDAT data has been inflated. Only the
+/// current chunk, the last 32 KiB of inflated data and two scanlines are kept,
+/// besides at most `INFLATE_STEP` bytes inflated and not given yet.
+///
+/// The CRC of an IDAT chunk can only be checked at its end, so with `CrcCheck::Strict`
+/// the scanlines inside a damaged chunk are given before the error.
+/// After an error the decoder must not be fed again.
+pub struct StreamDecoder {
+    options: DecodeOptions,
+    state: State,
+    buffer: Vec<u8>,
+    offset: usize, // position of buffer[0] in the datastream.
+    chunk_index: usize,
+    header: Option<ImageHeader>,
+    format: Option<ImageHeader>, // header of the scanlines given, known at the first IDAT.
+    palette: Option<Vec<[u8; 3]>>,
+    transparency: Option<Transparency>,
+    gamma: Option<Gamma>,
+    chromaticities: Option<Chromaticities>,
+    srgb: Option<RenderingIntent>,
+    text: HashMap<String, Text>,
+    metadata: Metadata,
+    unknown_chunks: Vec<Chunk>,
+    warnings: Vec<Warning>,
+    inflater: Inflater,
+    image_data: bool, // IDAT chunks seen.
+    image_data_done: bool,
+    scanlines: Vec<u8>, // inflated bytes not given as scanlines yet.
+    inflated: usize, // position of scanlines[0] in the inflated data.
+    passes: Vec<Pass>,
+    pass: usize,
+    row: usize,
+    previous: Vec<u8>,
+    current: Vec<u8>,
+    expand: Option<Transparency>, // the tRNS chunk, when every scanline gets it as alpha.
+    conversion: Option<SrgbConversion>, // of every scanline to sRGB.
+    expanded: Vec<u8>, // the scanline after the conversions.
+}
+
+impl Default for StreamDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::with_options(DecodeOptions::default())
+    }
+
+    pub fn with_options(options: DecodeOptions) -> Self {
+        let inflater = Inflater::new(options.inflate_options());
+        Self {
+            options,
+            state: State::Signature,
+            buffer: vec![],
+            offset: 0,
+            chunk_index: 0,
+            header: None,
+            format: None,
+            palette: None,
+            transparency: None,
+            gamma: None,
+            chromaticities: None,
+            srgb: None,
+            text: HashMap::new(),
+            metadata: Metadata::default(),
+            unknown_chunks: vec![],
+            warnings: vec![],
+            inflater,
+            image_data: false,
+            image_data_done: false,
+            scanlines: vec![],
+            inflated: 0,
+            passes: vec![],
+            pass: 0,
+            row: 0,
+            previous: vec![],
+            current: vec![],
+            expand: None,
+            conversion: None,
+            expanded: vec![],
+        }
+    }
+
+    /// Known once the IHDR chunk has been fed. From the first IDAT chunk on it is the header of the
+    /// scanlines, which `expand_trns` and `convert_to_srgb` change like for `PNGImage::from_bytes_with`.
+    pub fn header(&self) -> Option<&ImageHeader> {
+        self.format.as_ref().or(self.header.as_ref())
+    }
+
+    pub fn palette(&self) -> Option<&[[u8; 3]]> {
+        self.palette.as_deref()
+    }
+
+    /// None once it has been expanded into an alpha channel.
+    pub fn transparency(&self) -> Option<&Transparency> {
+        self.transparency.as_ref()
+    }
+
+    /// The colour chunks come before the image data, so they are known by the first scanline.
+    /// With `convert_to_srgb` they say sRGB from then on, and the palette is converted.
+    pub fn gamma(&self) -> Option<Gamma> {
+        self.gamma
+    }
+
+    pub fn chromaticities(&self) -> Option<Chromaticities> {
+        self.chromaticities
+    }
+
+    pub fn srgb(&self) -> Option<RenderingIntent> {
+        self.srgb
+    }
+
+    /// Text chunks may also come after the image data, all of them are here once `is_done`.
+    pub fn text(&self) -> &HashMap<String, Text> {
+        &self.text
+    }
+
+    /// Like `text`, the tIME chunk may come after the image data.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Ancillary chunks this decoder doesn´t know, like `PNGImage::unknown_chunks`.
+    pub fn unknown_chunks(&self) -> &[Chunk] {
+        &self.unknown_chunks
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Whether the IEND chunk has been reached, whatever comes after it is ignored.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::End)
+    }
+
+    /// Takes the next piece of the datastream, calling `on_row` with every scanline it completes.
+    pub fn feed<F: FnMut(Scanline<'_>)>(&mut self, bytes: &[u8], mut on_row: F) -> Result<(), Error> {
+        self.buffer.extend_from_slice(bytes);
+        let mut pos = 0;
+        let result = self.process(&mut pos, &mut on_row);
+        self.buffer.drain(..pos);
+        self.offset += pos;
+        result
+    }
+
+    fn process(&mut self, pos: &mut usize, on_row: &mut dyn FnMut(Scanline<'_>)) -> Result<(), Error> {
+        loop {
+            let available = self.buffer.len() - *pos;
+            let offset = self.offset + *pos;
+            match self.state {
+                State::Signature => {
+                    if available < 8 {
+                        return Ok(());
+                    }
+                    if &self.buffer[*pos..*pos + 8] != b"\x89PNG\r\n\x1a\n" {
+                        return Err(Error::BadSignature);
+                    }
+                    *pos += 8;
+                    self.state = State::ChunkHeader;
+                }
+                State::ChunkHeader => {
+                    if available < 8 {
+                        return Ok(());
+                    }
+                    let bytes = &self.buffer[*pos..*pos + 8];
+                    let length = u32::from_be_bytes(bytes[..4].try_into().expect("Valid length")) as usize;
+                    let name: [u8; 4] = bytes[4..].try_into().expect("Valid length");
+                    let typ = ChunkType::from_name(name, offset)?;
+                    if self.header.is_none() && typ != ChunkType::IHDR {
+                        return Err(Error::InvalidChunk { typ, offset, reason: "expected IHDR" });
+                    }
+                    self.options.limits.check_chunk(self.chunk_index, length, offset)?;
+                    self.check_order(typ, length, offset)?;
+                    if typ == ChunkType::IDAT {
+                        self.start_image_data()?;
+                        self.state = State::ImageData { start: offset, remaining: length, crc: update_crc32(0, &name) };
+                    } else {
+                        if self.image_data {
+                            self.finish_image_data(on_row)?;
+                        }
+                        self.state = State::Chunk { start: offset, typ, name, length };
+                    }
+                    *pos += 8;
+                }
+                State::ImageData { start, remaining, crc } => {
+                    if remaining == 0 {
+                        self.state = State::ImageDataCrc { start, crc };
+                        continue;
+                    }
+                    if available == 0 {
+                        return Ok(());
+                    }
+                    let n = remaining.min(available);
+                    let data = &self.buffer[*pos..*pos + n];
+                    // whatever comes after the end of the zlib stream is skipped.
+                    let consumed = match self.inflater.is_done() {
+                        true => n,
+                        false => inflate(&mut self.inflater, &mut self.scanlines, data)?.0,
+                    };
+                    let crc = update_crc32(crc, &data[..consumed]);
+                    *pos += consumed;
+                    self.state = State::ImageData { start, remaining: remaining - consumed, crc };
+                    self.emit_rows(on_row)?;
+                }
+                State::ImageDataCrc { start, crc } => {
+                    if available < 4 {
+                        return Ok(());
+                    }
+                    let stored = u32::from_be_bytes(self.buffer[*pos..*pos + 4].try_into().expect("Valid length"));
+                    self.check_crc(ChunkType::IDAT, stored, crc, start)?;
+                    *pos += 4;
+                    self.state = State::ChunkHeader;
+                }
+                State::Chunk { start, typ, name, length } => {
+                    if available < length + 4 {
+                        return Ok(());
+                    }
+                    let data = self.buffer[*pos..*pos + length].to_vec();
+                    let crc = u32::from_be_bytes(self.buffer[*pos + length..*pos + length + 4].try_into().expect("Valid length"));
+                    let computed_crc = update_crc32(update_crc32(0, &name), &data);
+                    let chunk = Chunk { typ, name, data, crc, offset: start, computed_crc };
+                    self.check_crc(typ, crc, computed_crc, start)?;
+                    *pos += length + 4;
+                    self.state = State::ChunkHeader;
+                    match typ {
+                        ChunkType::IHDR => {
+                            let header = ImageHeader::from_chunk(chunk)?;
+                            self.options.limits.check_header(&header, start)?;
+                            self.header = Some(header);
+                        }
+                        ChunkType::PLTE => self.palette = Some(palette_from_chunk(&chunk)?),
+                        ChunkType::tRNS => {
+                            let header = self.header.expect("IHDR is the first chunk");
+                            match transparency_from_chunk(&chunk, &header, self.palette.as_deref()) {
+                                Ok(trns) => self.transparency = Some(trns),
+                                Err(e) => self.warnings.push(Warning::ignored(self.chunk_index - 1, &chunk, e)),
+                            }
+                        }
+                        ChunkType::gAMA => match gamma_from_chunk(&chunk) {
+                            Ok(g) => self.gamma = Some(g),
+                            Err(e) => self.warnings.push(Warning::ignored(self.chunk_index - 1, &chunk, e)),
+                        },
+                        ChunkType::cHRM => match chromaticities_from_chunk(&chunk) {
+                            Ok(c) => self.chromaticities = Some(c),
+                            Err(e) => self.warnings.push(Warning::ignored(self.chunk_index - 1, &chunk, e)),
+                        },
+                        ChunkType::sRGB => match rendering_intent_from_chunk(&chunk) {
+                            Ok(intent) => self.srgb = Some(intent),
+                            Err(e) => self.warnings.push(Warning::ignored(self.chunk_index - 1, &chunk, e)),
+                        },
+                        ChunkType::tEXt | ChunkType::zTXt | ChunkType::iTXt => match text_from_chunk(&chunk, &self.options.inflate_options()) {
+                            Ok((keyword, value)) => {
+                                self.text.insert(keyword, value);
+                            }
+                            Err(e) => self.warnings.push(Warning::ignored(self.chunk_index - 1, &chunk, e)),
+                        },
+                        ChunkType::IEND => {
+                            if !self.image_data {
+                                return Err(Error::MissingChunk { typ: ChunkType::IDAT });
+                            }
+                            self.state = State::End;
+                        }
+                        ChunkType::AncyllaryChunk => self.unknown_chunks.push(chunk),
+                        _ => {
+                            let header = self.header.expect("IHDR is the first chunk");
+                            if let Err(e) = self.metadata.read_chunk(&chunk, &header, self.palette.as_deref(), self.image_data) {
+                                self.warnings.push(Warning::ignored(self.chunk_index - 1, &chunk, e));
+                            }
+                        }
+                    }
+                }
+                State::End => {
+                    *pos = self.buffer.len();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// The rules of `validate_chunks` for critical chunks, checked as they arrive.
+    fn check_order(&self, typ: ChunkType, length: usize, offset: usize) -> Result<(), Error> {
+        let greyscale = self.header.is_some_and(|h| matches!(h.color_type, ColorType::Greyscale | ColorType::GreyscaleA));
+        let reason = match typ {
+            ChunkType::IHDR if self.header.is_some() => "only one is allowed",
+            ChunkType::PLTE if self.palette.is_some() => "only one is allowed",
+            ChunkType::PLTE if self.image_data => "must come before IDAT",
+            ChunkType::PLTE if greyscale => "PLTE is not allowed in greyscale images",
+            ChunkType::IDAT if self.image_data_done => "IDAT chunks must be consecutive",
+            ChunkType::IEND if length != 0 => "IEND must be empty",
+            _ => return Ok(()),
+        };
+        Err(Error::InvalidChunk { typ, offset, reason })
+    }
+
+    fn check_crc(&mut self, typ: ChunkType, stored: u32, computed: u32, offset: usize) -> Result<(), Error> {
+        let index = self.chunk_index;
+        self.chunk_index += 1;
+        if stored == computed {
+            return Ok(());
+        }
+        match self.options.crc_check {
+            CrcCheck::Strict => Err(Error::CrcMismatch { typ, offset, stored, computed }),
+            CrcCheck::Lenient => {
+                self.warnings.push(Warning::CrcMismatch { index, offset, typ, stored, computed });
+                Ok(())
+            }
+        }
+    }
+
+    fn start_image_data(&mut self) -> Result<(), Error> {
+        if self.image_data {
+            return Ok(());
+        }
+        let header = self.header.expect("IHDR is the first chunk");
+        if header.color_type == ColorType::Indexed && self.palette.is_none() {
+            return Err(Error::MissingChunk { typ: ChunkType::PLTE });
+        }
+        let (width, height) = (header.width as usize, header.height as usize);
+        self.passes = match header.interlace {
+            Interlace::None => vec![(0, width, height, 0, 0, 1, 1)],
+            Interlace::Adam7 => ADAM7.iter().enumerate()
+                .map(|(pass, &(x0, y0, dx, dy))| {
+                    let (w, h) = adam7_pass_size(width, height, pass);
+                    (pass + 1, w, h, x0, y0, dx, dy)
+                })
+                .filter(|&(_, w, h, ..)| w > 0 && h > 0)
+                .collect(),
+        };
+        // tRNS and the colour chunks come before the image data, so they are known by the first
+        // scanline. The conversions go in the order of `PNGImage::from_bytes_with`.
+        let convert = self.options.convert_to_srgb && !is_srgb(self.gamma, self.chromaticities, self.srgb);
+        let keyed = matches!(self.transparency, Some(Transparency::Greyscale(_) | Transparency::Truecolour(..)));
+        let mut format = header;
+        if self.options.expand_trns || (convert && keyed) {
+            self.expand = self.transparency.take();
+            if self.expand.is_some() {
+                format = expand_trns_header(&header);
+            }
+        }
+        if convert {
+            let conversion = SrgbConversion::new(&format, self.gamma, self.chromaticities);
+            match format.color_type {
+                ColorType::Indexed => conversion.palette(self.palette.as_deref_mut().unwrap_or_default()),
+                _ => self.conversion = Some(conversion),
+            }
+            self.gamma = None;
+            self.chromaticities = None;
+            self.srgb = Some(RenderingIntent::Perceptual);
+        }
+        self.format = Some(format);
+        self.image_data = true;
+        Ok(())
+    }
+
+    fn finish_image_data(&mut self, on_row: &mut dyn FnMut(Scanline<'_>)) -> Result<(), Error> {
+        if self.image_data_done {
+            return Ok(());
+        }
+        self.image_data_done = true;
+        // the last chunk may have filled the output before the inflater gave all it had.
+        let mut status = Status::OutputFull;
+        while status == Status::OutputFull {
+            status = inflate(&mut self.inflater, &mut self.scanlines, &[])?.1;
+            self.emit_rows(on_row)?;
+        }
+        if !self.inflater.is_done() {
+            return Err(Error::DeflateError { offset: self.inflater.total_in(), reason: "unexpected end of data" });
+        }
+        if self.pass < self.passes.len() {
+            return Err(Error::TruncatedImageData { offset: self.inflated + self.scanlines.len() });
+        }
+        Ok(())
+    }
+
+    fn emit_rows(&mut self, on_row: &mut dyn FnMut(Scanline<'_>)) -> Result<(), Error> {
+        let header = self.header.expect("IHDR is the first chunk");
+        let bits_per_pixel = header.bits_per_pixel();
+        let mut pos = 0;
+        while self.pass < self.passes.len() {
+            let (pass, w, h, x0, y0, dx, dy) = self.passes[self.pass];
+            let cols = (w * bits_per_pixel).div_ceil(8);
+            if self.scanlines.len() - pos < cols + 1 {
+                break;
+            }
+            let line = &self.scanlines[pos..pos + cols + 1];
+            self.current.clear();
+            self.current.extend_from_slice(&line[1..]);
+            PNGImage::unfilter_row(line[0], &mut self.current, &self.previous, bits_per_pixel.div_ceil(8), self.inflated + pos)?;
+            let data = match (&self.expand, &self.conversion) {
+                (None, None) => &self.current,
+                (expand, conversion) => {
+                    self.expanded = match expand {
+                        Some(trns) => expand_trns(&ImageHeader { width: w as u32, height: 1, ..header }, &self.current, self.palette.as_deref(), trns),
+                        None => self.current.clone(),
+                    };
+                    if let Some(conversion) = conversion {
+                        conversion.pixels(&mut self.expanded);
+                    }
+                    &self.expanded
+                }
+            };
+            on_row(Scanline { pass, y: y0 + self.row * dy, x_start: x0, x_step: dx, data });
+
+            std::mem::swap(&mut self.previous, &mut self.current);
+            pos += cols + 1;
+            self.row += 1;
+            if self.row == h {
+                self.pass += 1;
+                self.row = 0;
+                self.previous.clear();
+            }
+        }
+        self.scanlines.drain(..pos);
+        self.inflated += pos;
+        Ok(())
+    }
+}
+
+/// Inflates at most `INFLATE_STEP` bytes at the end of `scanlines`, returns the bytes of `data` used.
+fn inflate(inflater: &mut Inflater, scanlines: &mut Vec<u8>, data: &[u8]) -> Result<(usize, Status), Error> {
+    let inflated = scanlines.len();
+    scanlines.resize(inflated + INFLATE_STEP, 0);
+    let (consumed, produced, status) = inflater.feed(data, &mut scanlines[inflated..])?;
+    scanlines.truncate(inflated + produced);
+    Ok((consumed, status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::noise;
+
+    type Chunks = Vec<(&'static [u8; 4], Vec<u8>)>;
+
+    fn chunk(name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let crc = update_crc32(update_crc32(0, name), data);
+        [&(data.len() as u32).to_be_bytes()[..], name, data, &crc.to_be_bytes()].concat()
+    }
+
+    // a zlib stream of stored blocks, split into IDAT chunks of at most `idat` bytes.
+    fn png(header: &ImageHeader, chunks: &[(&[u8; 4], Vec<u8>)], scanlines: &[u8], idat: usize) -> Vec<u8> {
+        let mut zlib = vec![0x78, 0x01];
+        let blocks: Vec<&[u8]> = scanlines.chunks(100).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            let len = block.len() as u16;
+            zlib.push((i + 1 == blocks.len()) as u8);
+            zlib.extend(len.to_le_bytes().into_iter().chain((!len).to_le_bytes()));
+            zlib.extend_from_slice(block);
+        }
+        let (a, b) = scanlines.iter().fold((1u32, 0u32), |(a, b), &x| ((a + x as u32) % 65521, (b + a + x as u32) % 65521));
+        zlib.extend((b << 16 | a).to_be_bytes());
+
+        let mut ihdr = [header.width.to_be_bytes(), header.height.to_be_bytes()].concat();
+        let color_type = match header.color_type {
+            ColorType::Greyscale => 0,
+            ColorType::Truecolour => 2,
+            ColorType::Indexed => 3,
+            ColorType::GreyscaleA => 4,
+            ColorType::TruecolourA => 6,
+        };
+        ihdr.extend([header.bit_depth, color_type, 0, 0, header.interlace as u8]);
+        let mut bytes = b"\x89PNG\r\n\x1a\n".to_vec();
+        bytes.extend(chunk(b"IHDR", &ihdr));
+        for (name, data) in chunks {
+            bytes.extend(chunk(name, data));
+        }
+        for data in zlib.chunks(idat) {
+            bytes.extend(chunk(b"IDAT", data));
+        }
+        bytes.extend(chunk(b"IEND", &[]));
+        bytes
+    }
+
+    // feeds `bytes` in pieces of 1 to `max` bytes, gives the decoder and the rows put together.
+    fn decode_in_pieces(bytes: &[u8], options: &DecodeOptions, max: u32) -> (StreamDecoder, Vec<u8>) {
+        let mut decoder = StreamDecoder::with_options(options.clone());
+        let mut data = vec![];
+        let mut rest = bytes;
+        for size in noise(bytes.len(), max, max) {
+            if rest.is_empty() {
+                break;
+            }
+            let (piece, next) = rest.split_at((size as usize + 1).min(rest.len()));
+            decoder.feed(piece, |row| data.extend_from_slice(row.data)).unwrap();
+            rest = next;
+        }
+        assert!(decoder.is_done());
+        (decoder, data)
+    }
+
+    #[test]
+    fn transparency_and_colour_in_random_pieces() {
+        let gama = 45455u32.to_be_bytes().to_vec();
+        let chrm: Vec<u8> = [31270u32, 32900, 64000, 33000, 21000, 71000, 15000, 6000].iter().flat_map(|x| x.to_be_bytes()).collect();
+        // (colour type, bit depth, samples the pixels are made of, extra chunks)
+        let images: [(ColorType, u8, &[u8], Chunks); 4] = [
+            (ColorType::Truecolour, 8, &[0, 100, 200], vec![(b"gAMA", gama.clone()), (b"cHRM", chrm), (b"tRNS", vec![0, 100, 0, 200, 0, 0])]),
+            (ColorType::Truecolour, 16, &[0, 100, 200], vec![(b"gAMA", gama.clone()), (b"tRNS", vec![0, 100, 200, 0, 0, 0])]),
+            (ColorType::Greyscale, 4, &[0x05, 0x5a, 0xaf], vec![(b"gAMA", gama.clone()), (b"tRNS", vec![0, 5])]),
+            (ColorType::Indexed, 2, &[0x1b, 0xe4, 0x55], vec![(b"gAMA", gama), (b"PLTE", vec![10, 20, 30, 40, 50, 60, 70, 80, 90, 250, 0, 5]), (b"tRNS", vec![0, 128])]),
+        ];
+        for (color_type, bit_depth, samples, chunks) in images {
+            let header = ImageHeader { width: 7, height: 5, bit_depth, color_type, interlace: Interlace::None };
+            let pixels: Vec<u8> = noise(header.row_bytes() * header.height as usize, bit_depth as u32, samples.len() as u32)
+                .into_iter()
+                .map(|i| samples[i as usize])
+                .collect();
+            // every row with filter type None.
+            let scanlines: Vec<u8> = pixels.chunks(header.row_bytes()).flat_map(|row| [&[0][..], row].concat()).collect();
+            let bytes = png(&header, &chunks, &scanlines, 30);
+            for (expand_trns, convert_to_srgb) in [(false, false), (true, false), (false, true), (true, true)] {
+                let options = DecodeOptions { expand_trns, convert_to_srgb, ..Default::default() };
+                let image = PNGImage::from_bytes_with(&bytes, &options).unwrap();
+                for max in [1, 5, 40] {
+                    let (decoder, data) = decode_in_pieces(&bytes, &options, max);
+                    assert_eq!(decoder.header(), Some(&image.header));
+                    assert_eq!(decoder.palette(), image.palette.as_deref());
+                    assert_eq!(decoder.transparency(), image.transparency.as_ref());
+                    assert_eq!((decoder.gamma(), decoder.chromaticities(), decoder.srgb()), (image.gamma, image.chromaticities, image.srgb));
+                    assert_eq!(data, image.data, "{color_type:?} {bit_depth} {expand_trns} {convert_to_srgb}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn adam7_split_anywhere() {
+        let (width, height) = (9, 7);
+        let header = ImageHeader { width: width as u32, height: height as u32, bit_depth: 8, color_type: ColorType::Truecolour, interlace: Interlace::Adam7 };
+        let pixels = noise(width * height * 3, 3, 256);
+        // the reduced image of every pass, its rows with filter type None.
+        let mut scanlines = vec![];
+        for (pass, &(x0, y0, dx, dy)) in ADAM7.iter().enumerate() {
+            let (w, h) = adam7_pass_size(width, height, pass);
+            for y in (0..h).filter(|_| w > 0).map(|row| y0 + row * dy) {
+                scanlines.push(0);
+                for x in (0..w).map(|col| x0 + col * dx) {
+                    scanlines.extend_from_slice(&pixels[(y * width + x) * 3..][..3]);
+                }
+            }
+        }
+        let bytes = png(&header, &[], &scanlines, 20);
+        assert_eq!(PNGImage::from_bytes(&bytes).unwrap().data, pixels);
+
+        let decode = |pieces: &[&[u8]]| {
+            let mut decoder = StreamDecoder::new();
+            let mut image = vec![0; pixels.len()];
+            for piece in pieces {
+                decoder.feed(piece, |row| {
+                    assert!((1..=7).contains(&row.pass));
+                    for (i, pixel) in row.data.chunks(3).enumerate() {
+                        let x = row.x_start + i * row.x_step;
+                        image[(row.y * width + x) * 3..][..3].copy_from_slice(pixel);
+                    }
+                }).unwrap();
+            }
+            assert!(decoder.is_done());
+            image
+        };
+        assert_eq!(decode(&bytes.chunks(1).collect::<Vec<_>>()), pixels);
+        // in two pieces, split inside the signature, a chunk header, its data and its CRC.
+        for split in 0..=bytes.len() {
+            assert_eq!(decode(&[&bytes[..split], &bytes[split..]]), pixels, "split at {split}");
+        }
+    }
+}
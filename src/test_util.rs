@@ -0,0 +1,8 @@
+/// Pseudo-random bytes below `spread`, the same ones for the same seed on every run.
+pub(crate) fn noise(n: usize, seed: u32, spread: u32) -> Vec<u8> {
+    let mut x = seed;
+    (0..n).map(|_| {
+        x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+        ((x >> 16) % spread) as u8
+    }).collect()
+}
@@ -0,0 +1,151 @@
+use crate::{png::*, zlib::{self, InflateOptions}, Error};
+
+// Text chunks hold a keyword and its text, each field ends with a null byte except the last one:
+//  * tEXt -> keyword, text in Latin-1.
+//  * zTXt -> keyword, compression method (0 = zlib), text in Latin-1 compressed.
+//  * iTXt -> keyword, compression flag, compression method, language tag, translated keyword,
+//            text in UTF-8 (compressed when the flag is 1). The flag and the method have no null after them.
+// Keywords are 1 to 79 printable Latin-1 characters without leading, trailing or consecutive spaces.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Text {
+    pub value: String,
+    /// Whether it was stored compressed, always for zTXt.
+    pub compressed: bool,
+    /// Only for iTXt, a tag like "en-GB" or "" when the language is not known.
+    pub language: Option<String>,
+    /// Only for iTXt, the keyword in that language, in UTF-8.
+    pub translated_keyword: Option<String>,
+}
+
+/// The keyword and the text of a tEXt, zTXt or iTXt chunk, compressed text is inflated with `inflate`.
+pub fn text_from_chunk(chunk: &Chunk, inflate: &InflateOptions) -> Result<(String, Text), Error> {
+    let invalid = |reason| Error::InvalidChunk { typ: chunk.typ, offset: chunk.offset, reason };
+    let (keyword, rest) = split_null(&chunk.data).ok_or(invalid("missing null separator after the keyword"))?;
+    if !valid_keyword(keyword) {
+        return Err(invalid("invalid keyword"));
+    }
+    let keyword = latin1(keyword);
+    let text = match chunk.typ {
+        ChunkType::tEXt => Text { value: latin1_text(rest).ok_or(invalid("null byte in the text"))?, compressed: false, language: None, translated_keyword: None },
+        ChunkType::zTXt => {
+            let (&method, compressed) = rest.split_first().ok_or(invalid("missing compression method"))?;
+            if method != 0 {
+                return Err(invalid("unknown compression method"));
+            }
+            let value = latin1_text(&zlib::inflate_with(compressed, inflate)?).ok_or(invalid("null byte in the text"))?;
+            Text { value, compressed: true, language: None, translated_keyword: None }
+        }
+        ChunkType::iTXt => {
+            let [flag, method, rest @ ..] = rest else {
+                return Err(invalid("missing compression flag and method"));
+            };
+            let compressed = match (flag, method) {
+                (0, _) => false,
+                (1, 0) => true,
+                (1, _) => return Err(invalid("unknown compression method")),
+                _ => return Err(invalid("invalid compression flag")),
+            };
+            let (language, rest) = split_null(rest).ok_or(invalid("missing null separator after the language"))?;
+            let (translated_keyword, value) = split_null(rest).ok_or(invalid("missing null separator after the translated keyword"))?;
+            // language tags are words of ASCII letters and digits joined by hyphens.
+            if !language.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'-') {
+                return Err(invalid("invalid language tag"));
+            }
+            let value = match compressed {
+                true => zlib::inflate_with(value, inflate)?,
+                false => value.to_vec(),
+            };
+            if value.contains(&0) {
+                return Err(invalid("null byte in the text"));
+            }
+            Text {
+                value: String::from_utf8(value).map_err(|_| invalid("text is not valid UTF-8"))?,
+                compressed,
+                language: Some(latin1(language)),
+                translated_keyword: Some(String::from_utf8(translated_keyword.to_vec()).map_err(|_| invalid("translated keyword is not valid UTF-8"))?),
+            }
+        }
+        _ => return Err(invalid("expected tEXt, zTXt or iTXt")),
+    };
+    Ok((keyword, text))
+}
+
+/// The bytes before the first null and the ones after it.
+pub(crate) fn split_null(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let i = bytes.iter().position(|&c| c == 0)?;
+    Some((&bytes[..i], &bytes[i + 1..]))
+}
+
+pub(crate) fn valid_keyword(keyword: &[u8]) -> bool {
+    (1..=79).contains(&keyword.len())
+        && keyword.iter().all(|&c| matches!(c, 32..=126 | 161..=255))
+        && keyword.first() != Some(&b' ')
+        && keyword.last() != Some(&b' ')
+        && !keyword.windows(2).any(|w| w == b"  ")
+}
+
+/// ISO 8859-1 maps every byte to the Unicode code point of the same value.
+pub(crate) fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&c| c as char).collect()
+}
+
+/// None if there is a null byte, the text must not have any.
+fn latin1_text(bytes: &[u8]) -> Option<String> {
+    match bytes.contains(&0) {
+        true => None,
+        false => Some(latin1(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(name: &[u8; 4], data: &[u8]) -> Result<(String, Text), Error> {
+        text_from_chunk(&Chunk::new(*name, data.to_vec(), 33).unwrap(), &InflateOptions::default())
+    }
+
+    fn reason(result: Result<(String, Text), Error>) -> &'static str {
+        match result {
+            Err(Error::InvalidChunk { reason, offset: 33, .. }) => reason,
+            result => panic!("{result:?}"),
+        }
+    }
+
+    #[test]
+    fn latin1_and_compressed_text() {
+        let (keyword, value) = text(b"tEXt", b"Author\0Jos\xe9").unwrap();
+        assert_eq!(keyword, "Author");
+        assert_eq!(value, Text { value: "José".into(), compressed: false, language: None, translated_keyword: None });
+
+        let compressed = [&b"Comment\0\0"[..], &zlib::deflate(b"caf\xe9 ".repeat(50).as_slice(), 6)].concat();
+        let (keyword, value) = text(b"zTXt", &compressed).unwrap();
+        assert_eq!(keyword, "Comment");
+        assert_eq!(value, Text { value: "café ".repeat(50), compressed: true, language: None, translated_keyword: None });
+    }
+
+    #[test]
+    fn international_text() {
+        let stored = text(b"iTXt", "Title\0\0\0pt-BR\0Título\0Olá, mundo".as_bytes()).unwrap();
+        let expected = Text { value: "Olá, mundo".into(), compressed: false, language: Some("pt-BR".into()), translated_keyword: Some("Título".into()) };
+        assert_eq!(stored, ("Title".into(), expected.clone()));
+
+        let compressed = [&b"Title\0\x01\0pt-BR\0"[..], "Título\0".as_bytes(), &zlib::deflate("Olá, mundo".as_bytes(), 9)].concat();
+        assert_eq!(text(b"iTXt", &compressed).unwrap(), ("Title".into(), Text { compressed: true, ..expected }));
+    }
+
+    #[test]
+    fn invalid_text() {
+        for keyword in [&b""[..], b" Title", b"Title ", b"Two  spaces", b"Tab\tin it", &[b'k'; 80]] {
+            assert_eq!(reason(text(b"tEXt", &[keyword, b"\0text"].concat())), "invalid keyword", "{keyword:?}");
+        }
+        assert_eq!(reason(text(b"tEXt", b"Title without a null")), "missing null separator after the keyword");
+        assert_eq!(reason(text(b"zTXt", b"Title")), "missing null separator after the keyword");
+        assert_eq!(reason(text(b"iTXt", b"Title\0\0\0en\0Title")), "missing null separator after the translated keyword");
+        assert_eq!(reason(text(b"iTXt", b"Title\0\0\0en")), "missing null separator after the language");
+        assert_eq!(reason(text(b"tEXt", b"Title\0null\0byte")), "null byte in the text");
+        assert_eq!(reason(text(b"zTXt", b"Title\0\x01")), "unknown compression method");
+        assert_eq!(reason(text(b"iTXt", b"Title\0\x02\0\0\0text")), "invalid compression flag");
+    }
+}
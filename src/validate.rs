@@ -0,0 +1,212 @@
+use crate::{png::*, Error};
+
+/// A rule of the spec about the order or the number of chunks that the datastream breaks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+    /// Index of the chunk, None when the problem is not about one chunk (a missing one, or data after IEND).
+    pub index: Option<usize>,
+    /// Position in the datastream of the chunk, or of the data after IEND.
+    pub offset: Option<usize>,
+    pub name: [u8; 4],
+    pub problem: &'static str,
+}
+
+impl Finding {
+    /// Problems with critical chunks, decoding stops at them. Data after IEND counts as
+    /// one too, although decoders just ignore it.
+    pub fn is_critical(&self) -> bool {
+        self.name[0] & 0x20 == 0
+    }
+}
+
+impl From<Finding> for Error {
+    fn from(finding: Finding) -> Self {
+        let typ = ChunkType::from_name(finding.name, 0).unwrap_or(ChunkType::AncyllaryChunk);
+        match finding.offset {
+            Some(offset) => Error::InvalidChunk { typ, offset, reason: finding.problem },
+            None => Error::MissingChunk { typ },
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Position {
+    First,
+    /// Also before IDAT.
+    BeforePlte,
+    /// When there is a PLTE, also before IDAT.
+    AfterPlte,
+    BeforeIdat,
+    Anywhere,
+    Last,
+}
+
+// (type, where it goes, whether there can be more than one), from the chunk ordering table of the spec.
+// The order of fcTL and fdAT is checked with their sequence numbers when decoding.
+// Chunks not in here can be anywhere between IHDR and IEND.
+const RULES: [(&[u8; 4], Position, bool); 25] = [
+    (b"IHDR", Position::First, false),
+    (b"PLTE", Position::BeforeIdat, false),
+    (b"IDAT", Position::Anywhere, true), // but one after the other.
+    (b"IEND", Position::Last, false),
+    (b"cHRM", Position::BeforePlte, false),
+    (b"cICP", Position::BeforePlte, false),
+    (b"cLLI", Position::BeforePlte, false),
+    (b"gAMA", Position::BeforePlte, false),
+    (b"iCCP", Position::BeforePlte, false),
+    (b"mDCV", Position::BeforePlte, false),
+    (b"sBIT", Position::BeforePlte, false),
+    (b"sRGB", Position::BeforePlte, false),
+    (b"bKGD", Position::AfterPlte, false),
+    (b"hIST", Position::AfterPlte, false),
+    (b"tRNS", Position::AfterPlte, false),
+    (b"eXIf", Position::BeforeIdat, false),
+    (b"pHYs", Position::BeforeIdat, false),
+    (b"sPLT", Position::BeforeIdat, true),
+    (b"tIME", Position::Anywhere, false),
+    (b"iTXt", Position::Anywhere, true),
+    (b"tEXt", Position::Anywhere, true),
+    (b"zTXt", Position::Anywhere, true),
+    (b"acTL", Position::BeforeIdat, false),
+    (b"fcTL", Position::Anywhere, true),
+    (b"fdAT", Position::Anywhere, true),
+];
+
+/// Checks every ordering and multiplicity rule of the spec on a whole datastream,
+/// the bytes after IEND included. Errors only when the chunks can´t be read at all.
+pub fn validate(bytes: &[u8]) -> Result<Vec<Finding>, Error> {
+    let chunks = read_chunks(bytes)?;
+    let mut findings = validate_chunks(&chunks);
+    let end = chunks.last().map(|c| c.offset + c.data.len() + 12).unwrap_or(8);
+    if end < bytes.len() {
+        findings.push(Finding { index: None, offset: Some(end), name: *b"IEND", problem: "data after IEND" });
+    }
+    Ok(findings)
+}
+
+/// Like `validate`, for chunks that were already read. Findings come in the order of the chunks,
+/// and the ones about missing chunks at the end.
+pub fn validate_chunks(chunks: &[Chunk]) -> Vec<Finding> {
+    let mut findings = vec![];
+    let mut report = |index: usize, problem| {
+        findings.push(Finding { index: Some(index), offset: Some(chunks[index].offset), name: chunks[index].name, problem });
+    };
+    let first = |name: &[u8; 4]| chunks.iter().position(|c| &c.name == name);
+    let (plte, idat) = (first(b"PLTE"), first(b"IDAT"));
+    let last = chunks.len().saturating_sub(1);
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let Some(&(_, position, multiple)) = RULES.iter().find(|rule| rule.0 == &chunk.name) else {
+            continue;
+        };
+        if !multiple && chunks[..i].iter().any(|c| c.name == chunk.name) {
+            report(i, "only one is allowed");
+            continue;
+        }
+        let after = |other: Option<usize>| other.is_some_and(|other| other < i);
+        match position {
+            Position::First if i != 0 => report(i, "IHDR must be the first chunk"),
+            Position::Last if i != last => report(i, "IEND must be the last chunk"),
+            Position::BeforePlte if after(plte) => report(i, "must come before PLTE"),
+            Position::BeforePlte | Position::AfterPlte | Position::BeforeIdat if after(idat) => report(i, "must come before IDAT"),
+            Position::AfterPlte if plte.is_some_and(|plte| i < plte) => report(i, "must come after PLTE"),
+            _ => {}
+        }
+    }
+    for i in 1..chunks.len() {
+        if chunks[i].typ == ChunkType::IDAT && after_idat(chunks, i) {
+            report(i, "IDAT chunks must be consecutive");
+        }
+    }
+    if let Some(i) = chunks.iter().position(|c| c.typ == ChunkType::IEND && !c.data.is_empty()) {
+        report(i, "IEND must be empty");
+    }
+    if let (Some(i), Some(j)) = (first(b"sRGB"), first(b"iCCP")) {
+        report(i.max(j), "sRGB and iCCP must not be both present");
+    }
+
+    let header = chunks.first().and_then(|c| ImageHeader::from_chunk(c.clone()).ok());
+    let color_type = header.map(|h| h.color_type);
+    if let Some(i) = plte {
+        if matches!(color_type, Some(ColorType::Greyscale | ColorType::GreyscaleA)) {
+            report(i, "PLTE is not allowed in greyscale images");
+        }
+    }
+    if let (Some(i), None) = (first(b"hIST"), plte) {
+        report(i, "hIST requires PLTE");
+    }
+
+    let mut missing = |name: &[u8; 4], problem| findings.push(Finding { index: None, offset: None, name: *name, problem });
+    if first(b"IHDR").is_none() {
+        missing(b"IHDR", "missing IHDR");
+    }
+    if color_type == Some(ColorType::Indexed) && plte.is_none() {
+        missing(b"PLTE", "PLTE is required for indexed-colour images");
+    }
+    if idat.is_none() {
+        missing(b"IDAT", "missing IDAT");
+    }
+    if first(b"IEND").is_none() {
+        missing(b"IEND", "missing IEND");
+    }
+    findings.sort_by_key(|f| f.index.unwrap_or(usize::MAX));
+    findings
+}
+
+/// Whether the IDAT at `i` comes after other chunks that were after an IDAT.
+fn after_idat(chunks: &[Chunk], i: usize) -> bool {
+    chunks[i - 1].typ != ChunkType::IDAT && chunks[..i - 1].iter().any(|c| c.typ == ChunkType::IDAT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (index, problem) of the findings about chunks with these names, IHDR of a truecolour image.
+    fn findings(names: &[&[u8; 4]]) -> Vec<(Option<usize>, &'static str)> {
+        let mut offset = 8;
+        let chunks: Vec<Chunk> = names.iter().map(|&&name| {
+            let data = match &name {
+                b"IHDR" => vec![0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0],
+                _ => vec![],
+            };
+            let chunk = Chunk::new(name, data, offset).unwrap();
+            offset += chunk.data.len() + 12;
+            chunk
+        }).collect();
+        validate_chunks(&chunks).into_iter().map(|f| (f.index, f.problem)).collect()
+    }
+
+    #[test]
+    fn valid() {
+        assert_eq!(findings(&[b"IHDR", b"gAMA", b"PLTE", b"tRNS", b"pHYs", b"IDAT", b"IDAT", b"tEXt", b"tEXt", b"IEND"]), []);
+    }
+
+    #[test]
+    fn position() {
+        assert_eq!(findings(&[b"gAMA", b"IHDR", b"IDAT", b"IEND"]), [(Some(1), "IHDR must be the first chunk")]);
+        assert_eq!(findings(&[b"IHDR", b"IDAT", b"IEND", b"tEXt"]), [(Some(2), "IEND must be the last chunk")]);
+        assert_eq!(findings(&[b"IHDR", b"PLTE", b"gAMA", b"IDAT", b"IEND"]), [(Some(2), "must come before PLTE")]);
+        assert_eq!(findings(&[b"IHDR", b"bKGD", b"PLTE", b"IDAT", b"IEND"]), [(Some(1), "must come after PLTE")]);
+    }
+
+    #[test]
+    fn duplicate() {
+        assert_eq!(findings(&[b"IHDR", b"gAMA", b"gAMA", b"IDAT", b"IEND"]), [(Some(2), "only one is allowed")]);
+        assert_eq!(findings(&[b"IHDR", b"IHDR", b"IDAT", b"IEND"]), [(Some(1), "only one is allowed")]);
+    }
+
+    #[test]
+    fn around_image_data() {
+        assert_eq!(findings(&[b"IHDR", b"IDAT", b"pHYs", b"IEND"]), [(Some(2), "must come before IDAT")]);
+        assert_eq!(findings(&[b"IHDR", b"IDAT", b"PLTE", b"IEND"]), [(Some(2), "must come before IDAT")]);
+        assert_eq!(findings(&[b"IHDR", b"IDAT", b"tEXt", b"IDAT", b"IEND"]), [(Some(3), "IDAT chunks must be consecutive")]);
+    }
+
+    #[test]
+    fn missing() {
+        assert_eq!(findings(&[b"IHDR", b"IEND"]), [(None, "missing IDAT")]);
+        assert_eq!(findings(&[b"IDAT"]), [(None, "missing IHDR"), (None, "missing IEND")]);
+        assert_eq!(findings(&[b"IHDR", b"IDAT", b"hIST", b"IEND"]), [(Some(2), "must come before IDAT"), (Some(2), "hIST requires PLTE")]);
+    }
+}
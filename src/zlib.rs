@@ -1,243 +1,1258 @@
-// inflate() method -> unwrap zlib header -> once I have compressed data
-//                  -> huffman -> lz77 -> be happy.
-
-//RFC 1950: https://www.rfc-editor.org/rfc/rfc1950.
-//RFC 1951: https://www.rfc-editor.org/rfc/rfc1951.
-//Concepts: https://www.youtube.com/watch?v=oi2lMBBjQ8s&t=6538s.
-//Good post: https://pyokagan.name/blog/2019-10-18-zlibinflate/. I used this blog to 
-// make my implementation cleaner, before this I didn´t have BitStream, and it all looked like a mess.
-#![allow(non_snake_case)]
-
-
-pub fn inflate(data: &[u8]) -> Vec<u8> {
-    let _CMF = data[0];// don´t care
-    let _FLG = data[1];// + L
-
-    let compressed_data = data[2..data.len() - 4].to_vec();
-
-    let _ADLER32 = u32::from_le_bytes(data[data.len()-4..].try_into().expect("msg"));// + RATIO
-
-    return decompress(compressed_data);
-}
-
-struct BitStream<'a> {
-    i: usize,
-    data: &'a [u8],
-    bit_position: u8,
-}
-impl<'a> BitStream<'a> {
-    fn new(data: &'a [u8]) -> Self {
-        Self { i: 0, data, bit_position: 8 }
-    }
-    fn next_byte(&mut self) -> u8{
-        //truncate current bits if we read whole new byte.
-        self.bit_position = 8;
-        self.i += 1;
-        return self.data[self.i];
-    }
-    fn next_bit(&mut self) -> usize {
-        if self.bit_position < 1 {
-            self.bit_position = 8;
-            self.i = self.i + 1;
-        }
-        let position = self.bit_position;
-        self.bit_position -= 1;
-        return (self.data[self.i] >> (8 - position)) as usize & 1;
-    }
-    fn next_bits(&mut self, n: usize) -> usize {
-        let mut acc = 0;
-        for i in 0..n {
-            acc = acc | (self.next_bit() << i);
-        }
-        return acc;
-    }
-}
-
-// we are with little indian now, but huffman codes!
-
-//A compressed data set consists of a series of blocks, corresponding
-//to successive blocks of input data.  The block sizes are arbitrary,
-//except that non-compressible blocks are limited to 65,535 bytes.
-
-
-const LENGTH: [usize; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
-const LENGTH_EXTRA: [usize; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
-const BACKWARDS: [usize; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
-const BACKWARDS_EXTRA: [usize; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
-const CL_TABLE: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
-
-
-fn decompress(stream: Vec<u8>) -> Vec<u8> {
-    let mut last_block = false;
-    let mut res = Vec::with_capacity(1000000000);
-    let mut it = BitStream::new(&stream);
-    let mut borrar = 0;
-
-    while !last_block {
-        let BFINAL = it.next_bit();
-        last_block = BFINAL == 1;
-        let BTYPE = it.next_bits(2);
-        if BTYPE == 0 {
-            let LEN = u16::from_le_bytes([it.next_byte(), it.next_byte()]);
-            let _NLEN = u16::from_le_bytes([it.next_byte(), it.next_byte()]);
-            for _ in 0..LEN {
-                res.push(it.next_byte());
-            }
-        }else {
-            let (mut ll_tree, mut d_tree) = fixed_trees();
-            if BTYPE == 2 {
-                borrar += 1;
-                let HLIT = it.next_bits(5);
-                let HDIST = it.next_bits(5);
-                let HCLEN = it.next_bits(4);
-                let mut cl_list = vec![0; 19];// 0 so we can truncate
-
-                for i in 0..HCLEN+4 {
-                    cl_list[CL_TABLE[i]] = it.next_bits(3);
-                }
-
-                let cl_tree = huffman_from_bit_length(&cl_list, 18);
-
-                let mut all = vec![];
-                while all.len() < (HLIT + HDIST + 258) {
-                    let symbol = process_symbol(&mut it, &cl_tree);
-                    if symbol <= 15 {
-                        all.push(symbol as usize);
-                    }else if symbol == 16 {
-                        let prev = *all.last().unwrap() as u8;
-                        let times = it.next_bits(2) + 3;
-                        all.append(&mut vec![prev as usize;times]);
-                    }else if symbol == 17 {
-                        let times = it.next_bits(3) + 3;
-                        all.append(&mut vec![0;times]);
-                    }else if symbol == 18 {
-                        let times = it.next_bits(7) + 11;
-                        all.append(&mut vec![0;times]);
-                    }
-                }
-                (ll_tree, d_tree) = (huffman_from_bit_length(&all[..(HLIT + 257)], 285),
-                                      huffman_from_bit_length(&all[(HLIT + 257)..], 285));
-            }
-            loop {
-                let symbol = process_symbol(&mut it, &ll_tree);
-                match symbol {
-                    0..=255 => res.push(symbol as u8),
-                    256 => break,
-                    _ => {
-                        let length = LENGTH[symbol as usize -257] + it.next_bits(LENGTH_EXTRA[symbol as usize -257]);
-                        let d = process_symbol(&mut it, &d_tree);
-                        let distance = BACKWARDS[d as usize] + it.next_bits(BACKWARDS_EXTRA[d as usize]);
-                        for _ in 0..length {
-                            res.push(res[res.len()-distance]);
-                        }
-                    },
-                }
-            }
-        }
-    }
-    println!("huffman dinamicos creados: {}", borrar);
-    return res;
-}
-
-fn process_symbol(it: &mut BitStream, tree: &HuffmanTree) -> u16 {
-    // if we find a 0 we go to the left, to the rigth if is a 1.
-    let mut current = tree;
-    while current.left != None && current.rigth != None {
-        let nb = it.next_bit();
-        match nb == 1 {
-            true => current = current.rigth.as_ref().unwrap(),
-            false => current = current.left.as_ref().unwrap(),
-        };
-    }
-    return current.symbol;
-}
-
-#[derive(PartialEq, Clone, Debug)]
-struct HuffmanTree {
-    symbol: u16,
-    left: Option<Box<HuffmanTree>>,
-    rigth: Option<Box<HuffmanTree>>,
-}
-
-impl HuffmanTree {
-    fn new() -> Self {
-        Self { symbol: 0, left: None, rigth: None }
-    }
-
-    fn add_code(&mut self, code: u16, length: usize, symbol: u16) {
-        // if we find a 0 we go to the left, to the rigth if is a 1.
-        let mut current = self;
-        for i in (0..length).rev() {
-            let bit = code & (1 << i);
-            if bit != 0 {
-                if let None = &mut current.rigth {// it is posible that we have not processed this code yet
-                    current.rigth = Some(Box::new(Self::new()));
-                }
-                current = current.rigth.as_mut().unwrap();
-            }else{
-                if let None = &mut current.left {
-                    current.left = Some(Box::new(Self::new()));
-                }
-                current = current.left.as_mut().unwrap();
-            }
-        }
-        current.symbol = symbol;
-    }
-}
-
-fn huffman_from_bit_length(bit_lengths: &[usize], until: u16) -> HuffmanTree {
-    //we can define the Huffman tree for an alphabet
-    //just by giving the bit lengths of the codes for each symbol of
-    //the alphabet in order; this is sufficient to determine the
-    //actual codes. Because this huffman codes follow these constraints:
-    //         * All codes of a given bit length have lexicographically
-    //           consecutive values, in the same order as the symbols
-    //           they represent;
-
-    //         * Shorter codes lexicographically precede longer codes.
-    let symbols = (0..=until).collect::<Vec<u16>>();
-    let mut huffman = HuffmanTree::new();
-    let MAX_BITS = *bit_lengths.iter().max().unwrap();
-    let mut bl_count = vec![0; MAX_BITS + 1];
-    let mut next_code = vec![0; MAX_BITS + 1];
-    bit_lengths.iter().for_each(|&x| bl_count[x] += 1);
-    let mut code = 0;
-    bl_count[0] = 0;
-    for bits in 1..=MAX_BITS {
-        code = (code + bl_count[bits - 1]) << 1;
-        next_code[bits] = code;
-    }
-
-    for n in 0..usize::min(symbols.len(), bit_lengths.len()) {
-        let len = bit_lengths[n];
-        if len != 0 {
-            huffman.add_code(next_code[len], len, symbols[n]);// idk if this is right
-            next_code[len] += 1;
-        }
-    }
-    return huffman;
-}
-
-fn fixed_trees() -> (HuffmanTree, HuffmanTree) {
-    //The Huffman codes for the two alphabets are fixed, and are not
-    //represented explicitly in the data.  The Huffman code lengths
-    //for the literal/length alphabet are:
-    let mut literal_length = vec![8usize; 288];
-    let back_distance = vec![5usize; 32];
-
-    for x in 144..256 {
-        literal_length[x] = 9;
-    }
-
-    for x in 256..280 {
-        literal_length[x] = 7;
-    }
-    //Literal/length values 286-287 will never actually
-    //occur in the compressed data, but participate in the code
-    //construction.
-
-    //Note that distance codes 30-
-    //31 will never actually occur in the compressed data.
-    return (huffman_from_bit_length(&literal_length, 285u16),
-            huffman_from_bit_length(&back_distance, 29u16));
-}
\ No newline at end of file
+// inflate() method -> unwrap zlib header -> once I have compressed data
+//                  -> huffman -> lz77 -> be happy.
+
+//RFC 1950: https://www.rfc-editor.org/rfc/rfc1950.
+//RFC 1951: https://www.rfc-editor.org/rfc/rfc1951.
+//Concepts: https://www.youtube.com/watch?v=oi2lMBBjQ8s&t=6538s.
+//Good post: https://pyokagan.name/blog/2019-10-18-zlibinflate/. I used this blog to 
+// make my implementation cleaner, before this I didn´t have BitStream, and it all looked like a mess.
+#![allow(non_snake_case)]
+use std::sync::OnceLock;
+
+use crate::{Error, Limits};
+
+#[derive(Clone, Debug)]
+pub struct InflateOptions {
+    /// Most bytes the inflated data can have, `Error::LimitExceeded` past it.
+    pub max_size: usize,
+    /// Compares the Adler-32 of the inflated data with the one at the end of the stream.
+    /// Without it the Adler-32 is still read, but not compared.
+    pub verify_checksum: bool,
+}
+
+impl Default for InflateOptions {
+    fn default() -> Self {
+        Self { max_size: Limits::default().max_decompressed, verify_checksum: true }
+    }
+}
+
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, Error> {
+    inflate_with(data, &InflateOptions::default())
+}
+
+pub fn inflate_with(data: &[u8], options: &InflateOptions) -> Result<Vec<u8>, Error> {
+    inflate_stream(data, None, options)
+}
+
+/// Inflates a stream compressed with a preset dictionary (FDICT), the bytes the back
+/// references can reach before the start of the data. DICTID must be its Adler-32.
+pub fn inflate_with_dictionary(data: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, Error> {
+    inflate_stream(data, Some(dictionary), &InflateOptions::default())
+}
+
+/// Checks CMF and FLG, returns if FDICT is set.
+fn check_header(CMF: u8, FLG: u8) -> Result<bool, Error> {
+    let error = |offset, reason| Err(Error::DeflateError { offset, reason });
+    if CMF & 0x0f != 8 {
+        return error(0, "compression method is not deflate");
+    }
+    if CMF >> 4 > 7 {
+        return error(0, "window bigger than 32 KiB");
+    }
+    if !(CMF as u16 * 256 + FLG as u16).is_multiple_of(31) {
+        return error(1, "FCHECK is wrong");
+    }
+    Ok(FLG & 0x20 != 0)
+}
+
+fn inflate_stream(data: &[u8], dictionary: Option<&[u8]>, options: &InflateOptions) -> Result<Vec<u8>, Error> {
+    let mut inflater = match dictionary {
+        Some(dictionary) => Inflater::with_dictionary(options.clone(), dictionary),
+        None => Inflater::new(options.clone()),
+    };
+    // images usually inflate to a few times their size, the buffer grows when that is not enough.
+    let mut res = vec![0; data.len().saturating_mul(4).min(options.max_size).max(1024)];
+    let (mut consumed, mut produced) = (0, 0);
+    loop {
+        let (used, written, status) = inflater.feed(&data[consumed..], &mut res[produced..])?;
+        consumed += used;
+        produced += written;
+        match status {
+            Status::Done => break,
+            // the inflater checks the limit, this is only in case it didn´t.
+            Status::OutputFull if res.len() > options.max_size => {
+                return Err(Error::LimitExceeded { limit: "inflated data too big", offset: consumed });
+            }
+            // one more byte than the limit is enough for the inflater to see it was passed.
+            Status::OutputFull => res.resize((res.len() * 2).min(options.max_size.saturating_add(1)), 0),
+            Status::NeedsInput => return Err(Error::DeflateError { offset: data.len(), reason: "unexpected end of data" }),
+        }
+    }
+    res.truncate(produced);
+    Ok(res)
+}
+
+/// Continues an Adler-32 with more bytes, start with 1.
+pub fn update_adler32(adler: u32, data: &[u8]) -> u32 {
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    // 5552 bytes is the most that can be added before b overflows.
+    for chunk in data.chunks(5552) {
+        for &x in chunk {
+            a += x as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// Checksum of the uncompressed data at the end of the zlib stream.
+pub fn adler32(data: &[u8]) -> u32 {
+    update_adler32(1, data)
+}
+
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// Inflates a zlib stream that arrives in pieces into a `Vec` for each one, see `Inflater`
+/// to inflate into a buffer of your own.
+#[derive(Default)]
+pub struct StreamInflater {
+    inflater: Inflater,
+}
+
+impl StreamInflater {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: InflateOptions) -> Self {
+        Self { inflater: Inflater::new(options) }
+    }
+
+    /// Adds compressed bytes, returns the data they inflated to.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut res = vec![];
+        let mut used = 0;
+        loop {
+            let produced = res.len();
+            res.resize(produced + WINDOW_SIZE, 0);
+            let (consumed, written, status) = self.inflater.feed(&data[used..], &mut res[produced..])?;
+            res.truncate(produced + written);
+            used += consumed;
+            if status != Status::OutputFull {
+                return Ok(res);
+            }
+        }
+    }
+
+    /// No more data will come, errors if the stream did not reach its end.
+    pub fn finish(&mut self) -> Result<Vec<u8>, Error> {
+        if !self.inflater.is_done() {
+            return Err(Error::DeflateError { offset: self.inflater.total_in(), reason: "unexpected end of data" });
+        }
+        Ok(vec![])
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.inflater.is_done()
+    }
+}
+
+/// Reads the bits of a deflate stream, first the lowest bit of each byte. Up to 64 bits are kept
+/// in `buffer`, loaded eight bytes at a time, so most reads are a shift and a mask. Past the end
+/// of the data it loads zeros, and consuming them is the "unexpected end of data" error.
+#[derive(Clone)]
+pub(crate) struct BitStream<'a> {
+    data: &'a [u8],
+    next: usize, // the byte loaded after the ones in buffer.
+    buffer: u64,
+    bits: u32, // bits in buffer.
+    start: usize, // position of data[0] in the stream, in bits.
+}
+impl<'a> BitStream<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, next: 0, buffer: 0, bits: 0, start: 0 }
+    }
+    /// Goes on with the next piece of a stream, after the bits `suspend` left from the piece before.
+    /// `position` is where those bits start in the stream.
+    pub(crate) fn resume(data: &'a [u8], buffer: u64, bits: u32, position: usize) -> Self {
+        Self { buffer, bits, start: position + bits as usize, ..Self::new(data) }
+    }
+    /// How many bytes of the data were used, and the bits of them still in the buffer, for `resume`.
+    pub(crate) fn suspend(&self) -> (usize, u64, u32) {
+        let used = usize::min(self.next, self.data.len());
+        let bits = self.bits - ((self.next - used) * 8) as u32;
+        let buffer = match bits {
+            64 => self.buffer,
+            // the refill may have left bits of the next byte above them.
+            _ => self.buffer & ((1 << bits) - 1),
+        };
+        (used, buffer, bits)
+    }
+    /// Bits read from the start of the stream.
+    pub(crate) fn position(&self) -> usize {
+        self.start + self.next * 8 - self.bits as usize
+    }
+    fn end(&self) -> usize {
+        self.start + self.data.len() * 8
+    }
+    /// Bits left before the end of the data.
+    pub(crate) fn available(&self) -> usize {
+        self.end().saturating_sub(self.position())
+    }
+    /// If the reads went past the end of the data, into the zeros.
+    pub(crate) fn overran(&self) -> bool {
+        self.position() > self.end()
+    }
+    /// The byte the last bit read came from, the end of the data if it ran out.
+    pub(crate) fn offset(&self) -> usize {
+        usize::min(self.position().saturating_sub(1) / 8, self.end() / 8)
+    }
+    pub(crate) fn error(&self, reason: &'static str) -> Error {
+        Error::DeflateError { offset: self.offset(), reason }
+    }
+    /// Loads bits in the buffer, up to 64, all the data left if it fits.
+    pub(crate) fn refill(&mut self) {
+        if let Some(bytes) = self.data.get(self.next..self.next + 8) {
+            // as many whole bytes as fit after the bits already there.
+            let word = u64::from_le_bytes(bytes.try_into().expect("Valid length"));
+            self.buffer |= word << self.bits;
+            self.next += (63 - self.bits as usize) / 8;
+            self.bits |= 56;
+            return;
+        }
+        while self.bits <= 56 {
+            let byte = self.data.get(self.next).copied().unwrap_or(0);
+            self.buffer |= (byte as u64) << self.bits;
+            self.next += 1;
+            self.bits += 8;
+        }
+    }
+    /// The next `n` bits, up to 32, without moving. Zeros past the end of the data.
+    pub(crate) fn peek_bits(&mut self, n: usize) -> usize {
+        if (self.bits as usize) < n {
+            self.refill();
+        }
+        (self.buffer & ((1 << n) - 1)) as usize
+    }
+    /// Moves `n` bits forward, up to 32, like `next_bits` but without reading them.
+    pub(crate) fn consume(&mut self, n: usize) -> Result<(), Error> {
+        if (self.bits as usize) < n {
+            self.refill();
+        }
+        self.buffer >>= n;
+        self.bits -= n as u32;
+        // only after loading the zeros past the end.
+        if self.next > self.data.len() && self.overran() {
+            return Err(self.error("unexpected end of data"));
+        }
+        Ok(())
+    }
+    pub(crate) fn next_bits(&mut self, n: usize) -> Result<usize, Error> {
+        let bits = self.peek_bits(n);
+        self.consume(n)?;
+        Ok(bits)
+    }
+    /// Skips the bits left in the current byte.
+    pub(crate) fn align(&mut self) {
+        self.buffer >>= self.bits % 8;
+        self.bits -= self.bits % 8;
+    }
+}
+
+// we are with little indian now, but huffman codes!
+
+//A compressed data set consists of a series of blocks, corresponding
+//to successive blocks of input data.  The block sizes are arbitrary,
+//except that non-compressible blocks are limited to 65,535 bytes.
+
+
+const LENGTH: [usize; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [usize; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const BACKWARDS: [usize; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const BACKWARDS_EXTRA: [usize; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+const CL_TABLE: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+
+/// What `Inflater::feed` stopped for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// All the input was used, more is needed to go on.
+    NeedsInput,
+    /// The output is full, feed again with more room.
+    OutputFull,
+    /// The stream ended, with its Adler-32 checked if the options say so.
+    Done,
+}
+
+// where the inflater is in the stream, each step reads a few bits at most.
+enum State {
+    Header,
+    Dictid,
+    BlockHeader,
+    StoredLength,
+    Stored { remaining: usize },
+    CodeLengthCodes { hlit: usize, hdist: usize, hclen: usize, lengths: [usize; 19], read: usize },
+    CodeLengths { hlit: usize, hdist: usize, table: HuffmanTable, lengths: Vec<usize> },
+    Symbols,
+    Copy { length: usize, distance: usize },
+    Trailer,
+    Done,
+}
+
+/// Inflates a zlib stream a piece at a time, into the buffers of the caller, keeping only the last
+/// 32 KiB of output for the back references. It stops wherever the input runs out or the output
+/// fills, inside a block too, and goes on from there with the next `feed`.
+pub struct Inflater {
+    options: InflateOptions,
+    state: State,
+    last_block: bool,
+    tables: Option<Box<(HuffmanTable, HuffmanTable)>>, // codes of the block, None for the fixed ones.
+    buffer: u64, // bits of the input read but not used yet.
+    bits: u32,
+    position: usize, // of buffer in the stream, in bits.
+    window: Vec<u8>,
+    written: usize, // bytes put in the window before this feed, the dictionary too.
+    dictionary: Option<u32>, // its Adler-32.
+    total_in: usize,
+    total_out: usize,
+    adler: u32,
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Self::new(InflateOptions::default())
+    }
+}
+
+impl Inflater {
+    pub fn new(options: InflateOptions) -> Self {
+        Self {
+            options,
+            state: State::Header,
+            last_block: false,
+            tables: None,
+            buffer: 0,
+            bits: 0,
+            position: 0,
+            window: vec![0; WINDOW_SIZE],
+            written: 0,
+            dictionary: None,
+            total_in: 0,
+            total_out: 0,
+            adler: 1,
+        }
+    }
+
+    /// For streams compressed with a preset dictionary (FDICT), see `inflate_with_dictionary`.
+    pub fn with_dictionary(options: InflateOptions, dictionary: &[u8]) -> Self {
+        let mut inflater = Self::new(options);
+        // back references can´t reach further than the window.
+        let tail = &dictionary[dictionary.len().saturating_sub(WINDOW_SIZE)..];
+        inflater.window[..tail.len()].copy_from_slice(tail);
+        inflater.written = tail.len();
+        inflater.dictionary = Some(adler32(dictionary));
+        inflater
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// Bytes of the stream used so far.
+    pub fn total_in(&self) -> usize {
+        self.total_in
+    }
+
+    /// Bytes inflated so far.
+    pub fn total_out(&self) -> usize {
+        self.total_out
+    }
+
+    /// Inflates `input` into `output`, returns how many bytes of each it used and why it stopped.
+    /// The input after the end of the stream is not used.
+    pub fn feed(&mut self, input: &[u8], output: &mut [u8]) -> Result<(usize, usize, Status), Error> {
+        let mut it = BitStream::resume(input, self.buffer, self.bits, self.position);
+        let mut produced = 0;
+        let mut hashed = 0;
+        let status = loop {
+            if matches!(self.state, State::Trailer) && self.options.verify_checksum {
+                self.adler = update_adler32(self.adler, &output[hashed..produced]);
+                hashed = produced;
+            }
+            let saved = it.clone();
+            match self.step(&mut it, output, &mut produced) {
+                Ok(None) => continue,
+                Ok(Some(status)) => break status,
+                // the step is read again from its start when more input comes, the input
+                // left is less than a step reads, so it all fits in the buffer.
+                Err(_) if it.overran() => {
+                    it = saved;
+                    it.refill();
+                    break Status::NeedsInput;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        if self.options.verify_checksum {
+            self.adler = update_adler32(self.adler, &output[hashed..produced]);
+        }
+        self.slide(&output[..produced]);
+        self.total_out += produced;
+        let (mut consumed, buffer, bits) = it.suspend();
+        (self.buffer, self.bits, self.position) = (buffer, bits, it.position());
+        if status == Status::Done {
+            // the whole bytes after the stream go back to the caller.
+            consumed -= usize::min((bits / 8) as usize, consumed);
+            (self.buffer, self.bits) = (0, 0);
+        }
+        self.total_in += consumed;
+        Ok((consumed, produced, status))
+    }
+
+    /// Keeps the last 32 KiB of what a feed produced.
+    fn slide(&mut self, output: &[u8]) {
+        let tail = &output[output.len().saturating_sub(WINDOW_SIZE)..];
+        let start = (self.written + output.len() - tail.len()) % WINDOW_SIZE;
+        let first = usize::min(tail.len(), WINDOW_SIZE - start);
+        self.window[start..start + first].copy_from_slice(&tail[..first]);
+        self.window[..tail.len() - first].copy_from_slice(&tail[first..]);
+        self.written += output.len();
+    }
+
+    /// Adds `length` bytes from `distance` back, in the output of this feed or in the window before it.
+    fn copy(&self, output: &mut [u8], produced: &mut usize, length: usize, distance: usize) {
+        let at = *produced;
+        if distance <= at && distance >= length {
+            output.copy_within(at - distance..at - distance + length, at);
+        } else if distance <= at {
+            // byte by byte, the match repeats its own start.
+            for i in at..at + length {
+                output[i] = output[i - distance];
+            }
+        } else {
+            for i in at..at + length {
+                output[i] = match distance <= i {
+                    true => output[i - distance],
+                    false => self.window[(self.written + i - distance) % WINDOW_SIZE],
+                };
+            }
+        }
+        *produced += length;
+    }
+
+    /// The next literal or match of the block, None at its end.
+    // inlined in the loop over the symbols, the calls cost as much as the decoding.
+    #[inline(always)]
+    fn next_token(&self, it: &mut BitStream, produced: usize) -> Result<Option<Token>, Error> {
+        let (ll_table, d_table) = match &self.tables {
+            Some(tables) => (&tables.0, &tables.1),
+            None => (&fixed_tables().0, &fixed_tables().1),
+        };
+        let symbol = process_symbol(it, ll_table)? as usize;
+        match symbol {
+            0..=255 => return Ok(Some(Token::Literal(symbol as u8))),
+            256 => return Ok(None),
+            286.. => return Err(it.error("invalid length symbol")),
+            _ => {}
+        }
+        let length = LENGTH[symbol - 257] + it.next_bits(LENGTH_EXTRA[symbol - 257])?;
+        let d = process_symbol(it, d_table)? as usize;
+        if d >= BACKWARDS.len() {
+            return Err(it.error("invalid distance symbol"));
+        }
+        let distance = BACKWARDS[d] + it.next_bits(BACKWARDS_EXTRA[d])?;
+        if distance > self.written + produced {
+            return Err(it.error("distance goes back before the start of the data"));
+        }
+        Ok(Some(Token::Match { length: length as u16, distance: distance as u16 }))
+    }
+
+    /// Reads the bits of one step, returns a status to stop. The reads go before changing
+    /// anything, so that when the input runs out the step can be done again.
+    fn step(&mut self, it: &mut BitStream, output: &mut [u8], produced: &mut usize) -> Result<Option<Status>, Error> {
+        let too_big = |it: &BitStream| Error::LimitExceeded { limit: "inflated data too big", offset: it.offset() };
+        match self.state {
+            State::Header => {
+                let CMF = it.next_bits(8)? as u8;
+                let FLG = it.next_bits(8)? as u8;
+                // DICTID comes before the compressed data when there is a dictionary.
+                self.state = match (check_header(CMF, FLG)?, self.dictionary) {
+                    (false, None) => State::BlockHeader,
+                    (false, Some(_)) => return Err(Error::DeflateError { offset: 1, reason: "stream without a preset dictionary" }),
+                    (true, None) => return Err(Error::DeflateError { offset: 1, reason: "stream needs a preset dictionary" }),
+                    (true, Some(_)) => State::Dictid,
+                };
+            }
+            State::Dictid => {
+                let DICTID = u32::from_be_bytes([it.next_bits(8)? as u8, it.next_bits(8)? as u8, it.next_bits(8)? as u8, it.next_bits(8)? as u8]);
+                if Some(DICTID) != self.dictionary {
+                    return Err(Error::DeflateError { offset: 2, reason: "DICTID is not the Adler-32 of the dictionary" });
+                }
+                self.state = State::BlockHeader;
+            }
+            State::BlockHeader => {
+                let BFINAL = it.next_bits(1)?;
+                let BTYPE = it.next_bits(2)?;
+                self.state = match BTYPE {
+                    0 => State::StoredLength,
+                    1 => State::Symbols,
+                    2 => {
+                        let HLIT = it.next_bits(5)?;
+                        let HDIST = it.next_bits(5)?;
+                        let HCLEN = it.next_bits(4)?;
+                        // 0 so we can truncate
+                        State::CodeLengthCodes { hlit: HLIT, hdist: HDIST, hclen: HCLEN + 4, lengths: [0; 19], read: 0 }
+                    }
+                    _ => return Err(it.error("reserved block type")),
+                };
+                self.last_block = BFINAL == 1;
+                self.tables = None;
+            }
+            State::StoredLength => {
+                it.align();
+                let LEN = it.next_bits(16)?;
+                let NLEN = it.next_bits(16)?;
+                if LEN != !NLEN & 0xffff {
+                    return Err(it.error("stored block length does not match its complement"));
+                }
+                if self.total_out + *produced + LEN > self.options.max_size {
+                    return Err(too_big(it));
+                }
+                self.state = State::Stored { remaining: LEN };
+            }
+            State::Stored { remaining } => {
+                let n = remaining.min(output.len() - *produced).min(it.available() / 8);
+                for _ in 0..n {
+                    output[*produced] = it.next_bits(8)? as u8;
+                    *produced += 1;
+                }
+                self.state = State::Stored { remaining: remaining - n };
+                match () {
+                    _ if remaining == n => self.state = self.end_of_block(),
+                    _ if *produced == output.len() => return Ok(Some(Status::OutputFull)),
+                    _ => return Ok(Some(Status::NeedsInput)),
+                }
+            }
+            State::CodeLengthCodes { hlit, hdist, hclen, mut lengths, read } => {
+                lengths[CL_TABLE[read]] = it.next_bits(3)?;
+                self.state = match read + 1 < hclen {
+                    true => State::CodeLengthCodes { hlit, hdist, hclen, lengths, read: read + 1 },
+                    false => {
+                        let table = HuffmanTable::new(&lengths, 7).map_err(|reason| it.error(reason))?;
+                        State::CodeLengths { hlit, hdist, table, lengths: Vec::with_capacity(hlit + hdist + 258) }
+                    }
+                };
+            }
+            State::CodeLengths { hlit, hdist, ref table, ref mut lengths } => {
+                let symbol = process_symbol(it, table)?;
+                let (length, times) = match symbol {
+                    0..=15 => (symbol as usize, 1),
+                    16 => (*lengths.last().ok_or(it.error("repeat code without a previous length"))?, it.next_bits(2)? + 3),
+                    17 => (0, it.next_bits(3)? + 3),
+                    _ => (0, it.next_bits(7)? + 11),
+                };
+                lengths.resize(lengths.len() + times, length);
+                if lengths.len() >= hlit + hdist + 258 {
+                    if lengths.len() > hlit + hdist + 258 {
+                        return Err(it.error("code lengths repeat past the end of the alphabets"));
+                    }
+                    let tables = (HuffmanTable::new(&lengths[..(hlit + 257)], 10).map_err(|reason| it.error(reason))?,
+                                  HuffmanTable::new(&lengths[(hlit + 257)..], 8).map_err(|reason| it.error(reason))?);
+                    self.tables = Some(Box::new(tables));
+                    self.state = State::Symbols;
+                }
+            }
+            State::Symbols => {
+                // far from the end of the input and of the output nothing can run out, so there
+                // is no need to stop after every symbol. A symbol with its distance reads 48 bits at most.
+                if it.available() >= 48 && output.len() - *produced >= MAX_MATCH {
+                    let room = self.options.max_size.saturating_sub(self.total_out);
+                    while it.available() >= 48 && output.len() - *produced >= MAX_MATCH {
+                        let Some(token) = self.next_token(it, *produced)? else {
+                            self.state = self.end_of_block();
+                            break;
+                        };
+                        if *produced + token.size() > room {
+                            return Err(too_big(it));
+                        }
+                        match token {
+                            Token::Literal(byte) => {
+                                output[*produced] = byte;
+                                *produced += 1;
+                            }
+                            Token::Match { length, distance } => self.copy(output, produced, length as usize, distance as usize),
+                        }
+                    }
+                    return Ok(None);
+                }
+                if *produced == output.len() {
+                    // the end of the block needs no room, only a symbol that adds bytes stops.
+                    let saved = it.clone();
+                    if self.next_token(it, *produced)?.is_some() {
+                        *it = saved;
+                        return Ok(Some(Status::OutputFull));
+                    }
+                    self.state = self.end_of_block();
+                    return Ok(None);
+                }
+                match self.next_token(it, *produced)? {
+                    None => self.state = self.end_of_block(),
+                    Some(token) if self.total_out + *produced + token.size() > self.options.max_size => return Err(too_big(it)),
+                    Some(Token::Literal(byte)) => {
+                        output[*produced] = byte;
+                        *produced += 1;
+                    }
+                    Some(Token::Match { length, distance }) => self.state = State::Copy { length: length as usize, distance: distance as usize },
+                }
+            }
+            State::Copy { length, distance } => {
+                let n = length.min(output.len() - *produced);
+                self.copy(output, produced, n, distance);
+                self.state = match n == length {
+                    true => State::Symbols,
+                    false => State::Copy { length: length - n, distance },
+                };
+                if n < length {
+                    return Ok(Some(Status::OutputFull));
+                }
+            }
+            State::Trailer => {
+                it.align();
+                let offset = it.position() / 8;
+                // big endian, like every number in the zlib format. It is read even when not
+                // checked, the stream is not whole without it.
+                let ADLER32 = u32::from_be_bytes([it.next_bits(8)? as u8, it.next_bits(8)? as u8, it.next_bits(8)? as u8, it.next_bits(8)? as u8]);
+                if self.options.verify_checksum && ADLER32 != self.adler {
+                    return Err(Error::Adler32Mismatch { offset, stored: ADLER32, computed: self.adler });
+                }
+                self.state = State::Done;
+            }
+            State::Done => return Ok(Some(Status::Done)),
+        }
+        Ok(None)
+    }
+
+    fn end_of_block(&self) -> State {
+        match self.last_block {
+            true => State::Trailer,
+            false => State::BlockHeader,
+        }
+    }
+}
+
+#[inline(always)]
+fn process_symbol(it: &mut BitStream, table: &HuffmanTable) -> Result<u16, Error> {
+    // the first bit read is the first of the code, so the table is indexed by the codes reversed.
+    let bits = it.peek_bits(MAX_CODE_LENGTH);
+    let mut entry = table.entries[bits & ((1 << table.primary_bits) - 1)];
+    if entry & SUBTABLE != 0 {
+        let sub_bits = entry & 0xff;
+        entry = table.entries[(entry >> 16) as usize + ((bits >> table.primary_bits) & ((1 << sub_bits) - 1))];
+    }
+    let length = entry & 0xff;
+    // incomplete codes leave some entries empty.
+    if length == 0 {
+        let error = it.error("invalid Huffman code");
+        // near the end it could be the zeros after the data instead.
+        it.consume(MAX_CODE_LENGTH)?;
+        return Err(error);
+    }
+    it.consume(length as usize)?;
+    Ok((entry >> 16) as u16)
+}
+
+const MAX_CODE_LENGTH: usize = 15;
+// an entry is the symbol in the high 16 bits and the length of its code in the low 8, or, with
+// this flag, where the subtable starts and how many bits after the primary ones index it.
+const SUBTABLE: u32 = 0x8000;
+
+/// Decodes a symbol with one lookup of the next `primary_bits` bits, two for the longer codes,
+/// like zlib and libdeflate do.
+struct HuffmanTable {
+    primary_bits: usize,
+    entries: Vec<u32>,
+}
+
+impl HuffmanTable {
+    fn new(bit_lengths: &[usize], primary_bits: usize) -> Result<Self, &'static str> {
+        //we can define the Huffman tree for an alphabet
+        //just by giving the bit lengths of the codes for each symbol of
+        //the alphabet in order; this is sufficient to determine the
+        //actual codes. Because this huffman codes follow these constraints:
+        //         * All codes of a given bit length have lexicographically
+        //           consecutive values, in the same order as the symbols
+        //           they represent;
+
+        //         * Shorter codes lexicographically precede longer codes.
+        let mut bl_count = [0usize; MAX_CODE_LENGTH + 1];
+        let mut next_code = [0usize; MAX_CODE_LENGTH + 1];
+        bit_lengths.iter().for_each(|&x| bl_count[x] += 1);
+        let mut code = 0;
+        bl_count[0] = 0;
+        for bits in 1..=MAX_CODE_LENGTH {
+            code = (code + bl_count[bits - 1]) << 1;
+            next_code[bits] = code;
+            if next_code[bits] + bl_count[bits] > 1 << bits {
+                return Err("more Huffman codes than their lengths allow");
+            }
+        }
+        let codes: Vec<(usize, usize, usize)> = bit_lengths.iter().enumerate()
+            .filter(|&(_, &len)| len != 0)
+            .map(|(symbol, &len)| {
+                let code = next_code[len];
+                next_code[len] += 1;
+                (symbol, len, reverse(code, len))
+            })
+            .collect();
+
+        // every prefix of primary bits the long codes start with gets a subtable as big as the longest one needs.
+        let mask = (1 << primary_bits) - 1;
+        let mut sub_bits = vec![0; 1 << primary_bits];
+        for &(_, len, code) in codes.iter().filter(|&&(_, len, _)| len > primary_bits) {
+            sub_bits[code & mask] = usize::max(sub_bits[code & mask], len - primary_bits);
+        }
+        let mut entries = vec![0u32; 1 << primary_bits];
+        for (prefix, &bits) in sub_bits.iter().enumerate().filter(|&(_, &bits)| bits > 0) {
+            entries[prefix] = ((entries.len() as u32) << 16) | SUBTABLE | bits as u32;
+            entries.resize(entries.len() + (1 << bits), 0);
+        }
+
+        // a code shorter than the bits used to index is in every entry that starts with it.
+        for (symbol, len, code) in codes {
+            let entry = ((symbol as u32) << 16) | len as u32;
+            if len <= primary_bits {
+                for i in (code..1 << primary_bits).step_by(1 << len) {
+                    entries[i] = entry;
+                }
+            } else {
+                let pointer = entries[code & mask];
+                let (start, bits) = ((pointer >> 16) as usize, (pointer & 0xff) as usize);
+                for i in ((code >> primary_bits)..1 << bits).step_by(1 << (len - primary_bits)) {
+                    entries[start + i] = entry;
+                }
+            }
+        }
+        Ok(Self { primary_bits, entries })
+    }
+}
+
+/// The first `length` bits of `code` in the other order.
+fn reverse(code: usize, length: usize) -> usize {
+    (0..length).fold(0, |acc, i| acc | ((code >> i) & 1) << (length - 1 - i))
+}
+
+/// Built the first time a block with fixed codes is found.
+fn fixed_tables() -> &'static (HuffmanTable, HuffmanTable) {
+    static FIXED: OnceLock<(HuffmanTable, HuffmanTable)> = OnceLock::new();
+    FIXED.get_or_init(|| {
+        //The Huffman codes for the two alphabets are fixed, and are not
+        //represented explicitly in the data.  The Huffman code lengths
+        //for the literal/length alphabet are:
+        let mut literal_length = vec![8usize; 288];
+        let back_distance = vec![5usize; 32];
+
+        literal_length[144..256].fill(9);
+        literal_length[256..280].fill(7);
+        //Literal/length values 286-287 will never actually
+        //occur in the compressed data, but participate in the code
+        //construction.
+
+        //Note that distance codes 30-
+        //31 will never actually occur in the compressed data.
+        (HuffmanTable::new(&literal_length, 9).expect("Valid fixed codes"),
+         HuffmanTable::new(&back_distance, 5).expect("Valid fixed codes"))
+    })
+}
+// The other way around: lz77 finds the repeated strings, then every block is written with
+// whichever of fixed Huffman codes, its own Huffman codes or no compression gives less bits.
+
+/// Compresses `data` into a zlib stream. `level` goes from 0, stored blocks without any
+/// compression, to 9, the slowest and smallest. Higher values count as 9.
+pub fn deflate(data: &[u8], level: u8) -> Vec<u8> {
+    let level = level.min(9);
+    // CMF: deflate with a 32 KiB window. FLG: how hard we tried, and the bits that make CMF * 256 + FLG a multiple of 31.
+    let CMF = 0x78u8;
+    let FLEVEL: u8 = match level {
+        0..=1 => 0,
+        2..=5 => 1,
+        6 => 2,
+        _ => 3,
+    };
+    let FLG = (FLEVEL << 6) + (31 - ((CMF as u16 * 256 + ((FLEVEL as u16) << 6)) % 31) as u8) % 31;
+    let mut out = BitWriter { bytes: vec![CMF, FLG], buffer: 0, bits: 0 };
+    if level == 0 {
+        write_stored(&mut out, data, true);
+    } else {
+        let tokens = lz77(data, LEVELS[level as usize]);
+        let mut start = 0;
+        let mut blocks = tokens.chunks(BLOCK_TOKENS).peekable();
+        while let Some(block) = blocks.next() {
+            let size: usize = block.iter().map(Token::size).sum();
+            write_block(&mut out, block, &data[start..start + size], blocks.peek().is_none());
+            start += size;
+        }
+        if tokens.is_empty() {
+            write_block(&mut out, &[], &[], true);
+        }
+    }
+    out.align();
+    out.bytes.extend_from_slice(&adler32(data).to_be_bytes());
+    out.bytes
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// The `n` low bits of `value`, the first one is the least significant one.
+    fn write(&mut self, value: u32, n: u32) {
+        self.buffer |= (value as u64) << self.bits;
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+    /// Pads the last byte with zeros.
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer = 0;
+            self.bits = 0;
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+impl Token {
+    /// Bytes of the data it stands for.
+    fn size(&self) -> usize {
+        match self {
+            Token::Literal(_) => 1,
+            Token::Match { length, .. } => *length as usize,
+        }
+    }
+}
+
+// (how many earlier positions to try, length that is good enough to stop looking, whether to
+// check if the next position has a longer match before taking one) for each level.
+const LEVELS: [(usize, usize, bool); 10] = [
+    (0, 0, false),
+    (4, 8, false),
+    (8, 16, false),
+    (16, 32, false),
+    (16, 32, true),
+    (32, 64, true),
+    (64, 128, true),
+    (128, 128, true),
+    (256, 258, true),
+    (1024, 258, true),
+];
+const BLOCK_TOKENS: usize = 16 * 1024;
+const HASH_BITS: usize = 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Positions of the earlier strings that start with the same 3 bytes, chained by their hash.
+struct Matcher<'a> {
+    data: &'a [u8],
+    head: Vec<u32>, // last position + 1 of every hash, 0 for none.
+    prev: Vec<u32>, // position + 1 of the one before with the same hash.
+    max_chain: usize,
+    nice: usize,
+}
+
+impl Matcher<'_> {
+    fn hash(&self, i: usize) -> usize {
+        let d = self.data;
+        ((d[i] as usize) << 10 ^ (d[i + 1] as usize) << 5 ^ d[i + 2] as usize) & ((1 << HASH_BITS) - 1)
+    }
+    fn insert(&mut self, i: usize) {
+        if i + MIN_MATCH <= self.data.len() {
+            let h = self.hash(i);
+            self.prev[i] = self.head[h];
+            self.head[h] = i as u32 + 1;
+        }
+    }
+    /// The longest earlier string that `i` repeats, as (length, distance). Length 0 when there is none.
+    fn find(&self, i: usize) -> (usize, usize) {
+        let max = (self.data.len() - i).min(MAX_MATCH);
+        if max < MIN_MATCH {
+            return (0, 0);
+        }
+        let (mut best, mut distance) = (0, 0);
+        let mut candidate = self.head[self.hash(i)];
+        for _ in 0..self.max_chain {
+            if candidate == 0 || i - (candidate as usize - 1) > WINDOW_SIZE {
+                break;
+            }
+            let c = candidate as usize - 1;
+            // the byte that would make it longer than the best one goes first, most candidates fail there.
+            if self.data[c + best.min(max - 1)] == self.data[i + best.min(max - 1)] {
+                let length = self.data[c..c + max].iter().zip(&self.data[i..i + max]).take_while(|(a, b)| a == b).count();
+                if length > best {
+                    (best, distance) = (length, i - c);
+                    if best >= self.nice.min(max) {
+                        break;
+                    }
+                }
+            }
+            candidate = self.prev[c];
+        }
+        match best >= MIN_MATCH {
+            true => (best, distance),
+            false => (0, 0),
+        }
+    }
+}
+
+fn lz77(data: &[u8], (max_chain, nice, lazy): (usize, usize, bool)) -> Vec<Token> {
+    let mut matcher = Matcher { data, head: vec![0; 1 << HASH_BITS], prev: vec![0; data.len()], max_chain, nice };
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = matcher.find(i);
+        matcher.insert(i);
+        // a longer match right after this position is worth a literal.
+        if lazy && length > 0 && length < nice && matcher.find(i + 1).0 > length {
+            tokens.push(Token::Literal(data[i]));
+            i += 1;
+            continue;
+        }
+        if length == 0 {
+            tokens.push(Token::Literal(data[i]));
+            i += 1;
+            continue;
+        }
+        tokens.push(Token::Match { length: length as u16, distance: distance as u16 });
+        for j in i + 1..i + length {
+            matcher.insert(j);
+        }
+        i += length;
+    }
+    tokens
+}
+
+/// Length and distance symbols with their extra bits, as (symbol, extra value, extra bits).
+fn length_symbol(length: usize) -> (usize, u32, u32) {
+    let i = LENGTH.partition_point(|&l| l <= length) - 1;
+    (257 + i, (length - LENGTH[i]) as u32, LENGTH_EXTRA[i] as u32)
+}
+
+fn distance_symbol(distance: usize) -> (usize, u32, u32) {
+    let i = BACKWARDS.partition_point(|&d| d <= distance) - 1;
+    (i, (distance - BACKWARDS[i]) as u32, BACKWARDS_EXTRA[i] as u32)
+}
+
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut literal_length = vec![8u8; 288];
+    literal_length[144..256].fill(9);
+    literal_length[256..280].fill(7);
+    (literal_length, vec![5u8; 30])
+}
+
+/// Code lengths of a Huffman code for these frequencies, none longer than `limit`.
+/// Symbols that never appear get 0, but there are always at least two codes.
+fn code_lengths(freqs: &[u32], limit: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    // like zlib, a lone symbol gets a partner so the code is complete.
+    for i in 0..2 {
+        if freqs.iter().filter(|&&f| f > 0).count() < 2 && freqs[i] == 0 {
+            freqs[i] = 1;
+        }
+    }
+    loop {
+        let lengths = huffman_lengths(&freqs);
+        if lengths.iter().all(|&l| l <= limit) {
+            return lengths;
+        }
+        // flatter frequencies give a shallower tree, until it fits.
+        for f in freqs.iter_mut().filter(|f| **f > 0) {
+            *f = f.div_ceil(2);
+        }
+    }
+}
+
+fn huffman_lengths(freqs: &[u32]) -> Vec<u8> {
+    use std::{cmp::Reverse, collections::BinaryHeap};
+    // nodes are the symbols first, then the ones made joining the two least frequent ones.
+    let mut parent: Vec<usize> = vec![0; freqs.len()];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = freqs.iter().enumerate()
+        .filter(|(_, &f)| f > 0)
+        .map(|(i, &f)| Reverse((f as u64, i)))
+        .collect();
+    while heap.len() > 1 {
+        let (Reverse((a, i)), Reverse((b, j))) = (heap.pop().expect("Two nodes"), heap.pop().expect("Two nodes"));
+        let node = parent.len();
+        parent.push(node);
+        (parent[i], parent[j]) = (node, node);
+        heap.push(Reverse((a + b, node)));
+    }
+    // parents come after their children, so going backwards their depth is already known.
+    let mut depth = vec![0u8; parent.len()];
+    for node in (0..parent.len()).rev() {
+        if parent[node] != node && parent[node] != 0 {
+            depth[node] = depth[parent[node]] + 1;
+        }
+    }
+    freqs.iter().enumerate().map(|(i, &f)| if f > 0 { depth[i] } else { 0 }).collect()
+}
+
+/// Codes from their lengths as in the decoder, with the bits reversed because
+/// Huffman codes go from their most significant bit.
+fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut bl_count = [0u32; 16];
+    lengths.iter().for_each(|&l| bl_count[l as usize] += 1);
+    bl_count[0] = 0;
+    let mut next_code = [0u32; 16];
+    let mut code = 0;
+    for bits in 1..16 {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+    lengths.iter().map(|&l| {
+        let code = next_code[l as usize];
+        next_code[l as usize] += 1;
+        match l {
+            0 => 0,
+            _ => code.reverse_bits() >> (32 - l as u32),
+        }
+    }).collect()
+}
+
+/// The code lengths of both alphabets with the repeat symbols 16, 17 and 18, as (symbol, extra value).
+fn run_lengths(lengths: &[u8]) -> Vec<(usize, u32)> {
+    let mut res = vec![];
+    let mut i = 0;
+    while i < lengths.len() {
+        let value = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&l| l == value).count();
+        if value == 0 && run >= 11 {
+            let n = run.min(138);
+            res.push((18, n as u32 - 11));
+            i += n;
+        } else if value == 0 && run >= 3 {
+            res.push((17, run as u32 - 3));
+            i += run;
+        } else {
+            res.push((value as usize, 0));
+            i += 1;
+            // the first one is written as it is, then repeated.
+            let mut left = run - 1;
+            while value != 0 && left >= 3 {
+                let n = left.min(6);
+                res.push((16, n as u32 - 3));
+                i += n;
+                left -= n;
+            }
+        }
+    }
+    res
+}
+
+fn write_stored(out: &mut BitWriter, data: &[u8], last: bool) {
+    let mut pieces = data.chunks(65535).peekable();
+    if data.is_empty() {
+        out.write(last as u32, 1);
+        out.write(0, 2);
+        out.align();
+        out.bytes.extend_from_slice(&[0, 0, 0xff, 0xff]);
+    }
+    while let Some(piece) = pieces.next() {
+        out.write((last && pieces.peek().is_none()) as u32, 1);
+        out.write(0, 2);
+        out.align();
+        let LEN = piece.len() as u16;
+        out.bytes.extend_from_slice(&LEN.to_le_bytes());
+        out.bytes.extend_from_slice(&(!LEN).to_le_bytes());
+        out.bytes.extend_from_slice(piece);
+    }
+}
+
+/// Writes the tokens of `raw` as the smallest of the three block types.
+fn write_block(out: &mut BitWriter, tokens: &[Token], raw: &[u8], last: bool) {
+    let mut ll_freqs = [0u32; 286];
+    let mut d_freqs = [0u32; 30];
+    ll_freqs[256] = 1;
+    for token in tokens {
+        match *token {
+            Token::Literal(x) => ll_freqs[x as usize] += 1,
+            Token::Match { length, distance } => {
+                ll_freqs[length_symbol(length as usize).0] += 1;
+                d_freqs[distance_symbol(distance as usize).0] += 1;
+            }
+        }
+    }
+    let ll_lengths = code_lengths(&ll_freqs, 15);
+    let d_lengths = code_lengths(&d_freqs, 15);
+    let HLIT = 257.max(286 - ll_lengths.iter().rev().take_while(|&&l| l == 0).count());
+    let HDIST = 1.max(30 - d_lengths.iter().rev().take_while(|&&l| l == 0).count());
+    let runs = run_lengths(&[&ll_lengths[..HLIT], &d_lengths[..HDIST]].concat());
+    let mut cl_freqs = [0u32; 19];
+    runs.iter().for_each(|&(symbol, _)| cl_freqs[symbol] += 1);
+    let cl_lengths = code_lengths(&cl_freqs, 7);
+    let HCLEN = 4.max(19 - CL_TABLE.iter().rev().take_while(|&&s| cl_lengths[s] == 0).count());
+
+    // bits of the data with each code, the extra bits are the same for both.
+    let extra: u64 = (257..286).map(|s| ll_freqs[s] as u64 * LENGTH_EXTRA[s - 257] as u64).sum::<u64>()
+        + (0..30).map(|s| d_freqs[s] as u64 * BACKWARDS_EXTRA[s] as u64).sum::<u64>();
+    let cost = |ll: &[u8], d: &[u8]| -> u64 {
+        ll_freqs.iter().zip(ll).map(|(&f, &l)| f as u64 * l as u64).sum::<u64>()
+            + d_freqs.iter().zip(d).map(|(&f, &l)| f as u64 * l as u64).sum::<u64>()
+            + extra
+    };
+    let (fixed_ll, fixed_d) = fixed_lengths();
+    let fixed = 3 + cost(&fixed_ll, &fixed_d);
+    let repeat_bits = [2, 3, 7];
+    let dynamic = 3 + 14 + 3 * HCLEN as u64
+        + runs.iter().map(|&(s, _)| cl_lengths[s] as u64 + if s >= 16 { repeat_bits[s - 16] } else { 0 }).sum::<u64>()
+        + cost(&ll_lengths, &d_lengths);
+    let stored = (raw.len() as u64 + 5 * raw.len().div_ceil(65535).max(1) as u64) * 8 + 7;
+
+    if stored < fixed.min(dynamic) {
+        write_stored(out, raw, last);
+        return;
+    }
+    out.write(last as u32, 1);
+    let (ll_lengths, d_lengths) = match dynamic < fixed {
+        true => {
+            out.write(2, 2);
+            out.write(HLIT as u32 - 257, 5);
+            out.write(HDIST as u32 - 1, 5);
+            out.write(HCLEN as u32 - 4, 4);
+            for &symbol in &CL_TABLE[..HCLEN] {
+                out.write(cl_lengths[symbol] as u32, 3);
+            }
+            let cl_codes = canonical_codes(&cl_lengths);
+            for &(symbol, value) in &runs {
+                out.write(cl_codes[symbol], cl_lengths[symbol] as u32);
+                if symbol >= 16 {
+                    out.write(value, repeat_bits[symbol - 16] as u32);
+                }
+            }
+            (ll_lengths, d_lengths)
+        }
+        false => {
+            out.write(1, 2);
+            (fixed_ll, fixed_d)
+        }
+    };
+    let (ll_codes, d_codes) = (canonical_codes(&ll_lengths), canonical_codes(&d_lengths));
+    for token in tokens {
+        match *token {
+            Token::Literal(x) => out.write(ll_codes[x as usize], ll_lengths[x as usize] as u32),
+            Token::Match { length, distance } => {
+                let (symbol, value, bits) = length_symbol(length as usize);
+                out.write(ll_codes[symbol], ll_lengths[symbol] as u32);
+                out.write(value, bits);
+                let (symbol, value, bits) = distance_symbol(distance as usize);
+                out.write(d_codes[symbol], d_lengths[symbol] as u32);
+                out.write(value, bits);
+            }
+        }
+    }
+    out.write(ll_codes[256], ll_lengths[256] as u32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::noise;
+
+    fn first_block_type(stream: &[u8]) -> u8 {
+        (stream[2] >> 1) & 3
+    }
+
+    /// Inflates with one byte of input and one of output at a time, so every step has to stop and go on.
+    fn inflate_bytewise(mut inflater: Inflater, stream: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut res = vec![];
+        let mut consumed = 0;
+        loop {
+            let mut byte = [0];
+            let input = &stream[consumed..(consumed + 1).min(stream.len())];
+            let (used, produced, status) = inflater.feed(input, &mut byte)?;
+            consumed += used;
+            res.extend_from_slice(&byte[..produced]);
+            match status {
+                Status::Done => break,
+                Status::NeedsInput if consumed == stream.len() => return Err(Error::DeflateError { offset: consumed, reason: "unexpected end of data" }),
+                _ => {}
+            }
+        }
+        assert_eq!(inflater.total_in(), consumed);
+        assert_eq!(inflater.total_out(), res.len());
+        Ok(res)
+    }
+
+    #[test]
+    fn resumes_in_every_block_type() {
+        let text = b"a few words, a few more words and a few words again".to_vec();
+        let skewed = noise(100_000, 1, 20);
+        let cases = [(text.clone(), 6, 1), (skewed.clone(), 6, 2), (text, 0, 0), (skewed.clone(), 0, 0), (vec![], 6, 1), (noise(100_000, 1, 256), 9, 0)];
+        for (data, level, block_type) in cases {
+            let stream = deflate(&data, level);
+            assert_eq!(first_block_type(&stream), block_type);
+            assert!(inflate_bytewise(Inflater::default(), &stream).unwrap() == data);
+            // the bytes after the end of the stream are not used.
+            let mut inflater = Inflater::default();
+            let mut output = vec![0; data.len()];
+            let padded = [&stream[..], b"next"].concat();
+            assert_eq!(inflater.feed(&padded, &mut output).unwrap(), (stream.len(), data.len(), Status::Done));
+            // nor the ones of a stream cut short.
+            assert!(inflate_bytewise(Inflater::default(), &stream[..stream.len() - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn stored_data_past_the_limit() {
+        let data = noise(180_000, 1, 256);
+        let stream = deflate(&data, 0);
+        let options = |max_size| InflateOptions { max_size, ..Default::default() };
+        assert!(matches!(inflate_with(&stream, &options(100_000)), Err(Error::LimitExceeded { .. })));
+        assert!(matches!(inflate_bytewise(Inflater::new(options(100_000)), &stream), Err(Error::LimitExceeded { .. })));
+        assert!(inflate_with(&stream, &options(data.len())).unwrap() == data);
+    }
+
+    #[test]
+    fn matches_reach_into_the_window() {
+        // the same random bytes 32000 apart, with zeros in between.
+        let start = noise(2000, 1, 256);
+        let data = [&start[..], &vec![0; 30_000], &start].concat();
+        let stream = deflate(&data, 6);
+        assert!(stream.len() < 3000, "the second copy should be a match");
+        // every feed has room for 1000 bytes, so the matches are in the output of the feeds before.
+        let mut inflater = Inflater::default();
+        let (mut consumed, mut res) = (0, vec![]);
+        loop {
+            let mut output = [0; 1000];
+            let (used, produced, status) = inflater.feed(&stream[consumed..], &mut output).unwrap();
+            consumed += used;
+            res.extend_from_slice(&output[..produced]);
+            if status == Status::Done {
+                break;
+            }
+        }
+        assert!(res == data);
+    }
+
+    #[test]
+    fn preset_dictionary() {
+        let dictionary = b"words the data is likely to have, ";
+        let data = [&dictionary[..], b"and the data"].concat();
+        // FDICT set, and CMF * 256 + FLG a multiple of 31.
+        let mut out = BitWriter { bytes: vec![0x78, 0x20], buffer: 0, bits: 0 };
+        out.bytes.extend_from_slice(&adler32(dictionary).to_be_bytes());
+        let mut tokens = vec![Token::Match { length: dictionary.len() as u16, distance: dictionary.len() as u16 }];
+        tokens.extend(b"and the data".iter().map(|&x| Token::Literal(x)));
+        write_block(&mut out, &tokens, &data, true);
+        out.align();
+        out.bytes.extend_from_slice(&adler32(&data).to_be_bytes());
+        let stream = out.bytes;
+
+        assert!(inflate_with_dictionary(&stream, dictionary).unwrap() == data);
+        assert!(inflate_bytewise(Inflater::with_dictionary(InflateOptions::default(), dictionary), &stream).unwrap() == data);
+        assert!(matches!(inflate(&stream), Err(Error::DeflateError { reason: "stream needs a preset dictionary", .. })));
+        assert!(inflate_with_dictionary(&stream, b"other words").is_err());
+    }
+}